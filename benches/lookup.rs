@@ -0,0 +1,110 @@
+//! Compares component lookups and iteration against the `HashMap` index `ComponentManager` used
+//! before it became a sparse set, run with `cargo bench --bench lookup`
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use snake::core::ecs::{entities::Entity, simulation::Simulation, Component};
+
+const ENTITIES: usize = 100_000;
+
+struct Health(u8);
+
+impl Component for Health {}
+
+/// The layout `ComponentManager` had before the sparse set, dense vectors indexed through a
+/// `HashMap` from entity index to position
+struct HashIndexed<T> {
+    components: Vec<T>,
+    entity_ids: Vec<Entity>,
+    index: HashMap<usize, usize>,
+}
+
+impl<T> HashIndexed<T> {
+    fn new() -> Self {
+        Self {
+            components: vec![],
+            entity_ids: vec![],
+            index: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, entity: Entity, component: T) {
+        self.components.push(component);
+        self.entity_ids.push(entity);
+        self.index.insert(entity.index(), self.entity_ids.len() - 1);
+    }
+
+    fn get(&self, entity: Entity) -> Option<&T> {
+        let index = *self.index.get(&entity.index())?;
+        if self.entity_ids[index] == entity {
+            Some(&self.components[index])
+        } else {
+            None
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Entity, &T)> {
+        self.entity_ids.iter().copied().zip(self.components.iter())
+    }
+}
+
+fn setup() -> (Simulation, HashIndexed<Health>, Vec<Entity>) {
+    let mut simulation = Simulation::new();
+    simulation.register_component::<Health>();
+    let mut hashed = HashIndexed::new();
+    let entities = (0..ENTITIES)
+        .map(|_| simulation.create_entity())
+        .collect::<Vec<_>>();
+    for (position, entity) in entities.iter().enumerate() {
+        simulation
+            .add_component_to_entity(*entity, Health(position as u8))
+            .unwrap();
+        hashed.add(*entity, Health(position as u8));
+    }
+    (simulation, hashed, entities)
+}
+
+fn lookup(c: &mut Criterion) {
+    let (simulation, hashed, entities) = setup();
+    let query = simulation.entity_manager.query::<&Health>().unwrap();
+
+    let mut group = c.benchmark_group("lookup");
+    group.bench_function("sparse_set", |b| {
+        b.iter(|| {
+            for entity in entities.iter() {
+                black_box(query.get(*entity));
+            }
+        })
+    });
+    group.bench_function("hash_map", |b| {
+        b.iter(|| {
+            for entity in entities.iter() {
+                black_box(hashed.get(*entity));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn iterate(c: &mut Criterion) {
+    let (simulation, hashed, _) = setup();
+    let query = simulation.entity_manager.query::<&Health>().unwrap();
+
+    let mut group = c.benchmark_group("iterate");
+    group.bench_function("sparse_set", |b| {
+        b.iter(|| query.iter().map(|(_, health)| health.0 as u64).sum::<u64>())
+    });
+    group.bench_function("hash_map", |b| {
+        b.iter(|| {
+            hashed
+                .iter()
+                .map(|(_, health)| health.0 as u64)
+                .sum::<u64>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, lookup, iterate);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+use super::{
+    component::{Apple, Collidable, Debugging, Position, Render, Snek, Tail, TurnQueue, Velocity},
+    ecs::{bundle::Bundle, entities::Entity, entitymanager::EntityManager, error::EcsError},
+};
+
+pub struct SnakeHeadBundle {
+    pub position: Position,
+    pub velocity: Velocity,
+}
+
+pub struct AppleBundle {
+    pub position: Position,
+}
+
+pub struct SegmentBundle {
+    pub position: Position,
+}
+
+pub struct WallBundle {
+    pub position: Position,
+}
+
+impl Bundle for SnakeHeadBundle {
+    fn insert(self, em: &mut EntityManager, entity: Entity) -> Result<(), EcsError> {
+        (
+            Snek,
+            self.position,
+            self.velocity,
+            TurnQueue::default(),
+            Tail::default(),
+            Render { sprite: '🟢' },
+            Collidable,
+            Debugging::default(),
+        )
+            .insert(em, entity)
+    }
+}
+
+impl Bundle for AppleBundle {
+    fn insert(self, em: &mut EntityManager, entity: Entity) -> Result<(), EcsError> {
+        (
+            Apple,
+            self.position,
+            Render { sprite: '🍎' },
+            Collidable,
+            Debugging::default(),
+        )
+            .insert(em, entity)
+    }
+}
+
+impl Bundle for SegmentBundle {
+    fn insert(self, em: &mut EntityManager, entity: Entity) -> Result<(), EcsError> {
+        (self.position, Render { sprite: '🟩' }, Collidable).insert(em, entity)
+    }
+}
+
+impl Bundle for WallBundle {
+    fn insert(self, em: &mut EntityManager, entity: Entity) -> Result<(), EcsError> {
+        (self.position, Render { sprite: '▩' }, Collidable).insert(em, entity)
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+/// Turns real elapsed time into a whole number of fixed simulation steps
+///
+/// Time that is left over after the last whole step is carried over to the next call, so the
+/// simulation keeps the same pace however long updating and rendering take. When the game falls
+/// more than `max_steps` behind the backlog is dropped instead of being caught up on, which
+/// would otherwise make the game even slower.
+pub struct Clock {
+    previous: Instant,
+    lag: Duration,
+    max_steps: u32,
+}
+
+impl Clock {
+    pub fn new(max_steps: u32) -> Self {
+        Self {
+            previous: Instant::now(),
+            lag: Duration::ZERO,
+            max_steps,
+        }
+    }
+
+    /// Number of steps of length `delta` to run for the time that passed since the last call
+    pub fn steps(&mut self, delta: Duration) -> u32 {
+        let now = Instant::now();
+        let real = now - self.previous;
+        self.previous = now;
+        self.steps_for(real, delta)
+    }
+
+    /// Time left until the next step is due
+    pub fn until_next_step(&self, delta: Duration) -> Duration {
+        delta.saturating_sub(self.lag)
+    }
+
+    fn steps_for(&mut self, real: Duration, delta: Duration) -> u32 {
+        self.lag += real;
+        let mut steps = 0;
+        while self.lag >= delta && !delta.is_zero() {
+            if steps == self.max_steps {
+                self.lag = Duration::ZERO;
+                break;
+            }
+            self.lag -= delta;
+            steps += 1;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn should_carry_over_time_between_steps() {
+        let mut clock = Clock::new(5);
+        let delta = Duration::from_millis(10);
+
+        assert_eq!(clock.steps_for(Duration::from_millis(15), delta), 1);
+        assert_eq!(clock.until_next_step(delta), Duration::from_millis(5));
+        assert_eq!(clock.steps_for(Duration::from_millis(15), delta), 2);
+        assert_eq!(clock.steps_for(Duration::from_millis(4), delta), 0);
+    }
+
+    #[test]
+    pub fn should_drop_backlog_past_max_steps() {
+        let mut clock = Clock::new(3);
+        let delta = Duration::from_millis(10);
+
+        assert_eq!(clock.steps_for(Duration::from_millis(100), delta), 3);
+        assert_eq!(clock.until_next_step(delta), delta);
+    }
+}
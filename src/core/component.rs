@@ -1,17 +1,15 @@
-use super::ecs::Component;
+use std::collections::VecDeque;
 
-pub enum Types {
-    Snek,
-    Apple,
-}
+use super::ecs::{
+    entities::{Entity, Remap, RemapEntities},
+    Component, Tag,
+};
 
-pub struct Snek {
-    pub is_alive: bool,
-}
+#[derive(Default)]
+pub struct Snek;
 
-pub struct Apple {
-    pub is_alive: bool,
-}
+#[derive(Default)]
+pub struct Apple;
 
 pub struct Position {
     pub x: i16,
@@ -23,41 +21,83 @@ pub struct Velocity {
     pub y: i16,
 }
 
-pub struct Render {
-    pub sprite: char,
+/// Turns the player made that the snake has not taken yet, it takes one per move
+///
+/// Turns are headings as `(x, y)`, like a `Velocity`. A turn is only queued when it changes the
+/// heading the snake will have once the turns before it are taken and does not reverse it, so
+/// the snake can not run back into itself and quick taps within one move are not lost.
+#[derive(Default)]
+pub struct TurnQueue {
+    turns: VecDeque<(i16, i16)>,
+}
+
+impl TurnQueue {
+    /// Turns that can be queued up at once, later ones are dropped
+    pub const CAPACITY: usize = 3;
+
+    /// Queues `turn` for a snake that is currently heading towards `heading`, returns whether
+    /// the turn was queued
+    pub fn push(&mut self, heading: (i16, i16), turn: (i16, i16)) -> bool {
+        let last = self.turns.back().copied().unwrap_or(heading);
+        let reverses = (last.0 + turn.0, last.1 + turn.1) == (0, 0);
+        if turn == last || reverses || self.turns.len() >= Self::CAPACITY {
+            return false;
+        }
+        self.turns.push_back(turn);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<(i16, i16)> {
+        self.turns.pop_front()
+    }
 }
 
-pub struct Collidable {
-    pub collided: bool,
+/// Segments of a snake's tail, ordered from the one right behind the head to the tip
+///
+/// Segments are entities of their own that take the place of the one in front of them on every
+/// move. `pending` segments are added at the tip, one per move.
+#[derive(Default)]
+pub struct Tail {
+    pub segments: Vec<Entity>,
+    pub pending: u32,
 }
 
-pub struct Arena {
-    pub width: i16,
-    pub height: i16,
+impl RemapEntities for Tail {
+    fn remap_entities(&mut self, remap: &Remap) {
+        self.segments.remap_entities(remap);
+    }
 }
 
-pub struct Type {
-    pub typ: Types,
+pub struct Render {
+    pub sprite: char,
 }
 
+#[derive(Default)]
+pub struct Collidable;
+
+/// Marks a snake that ran into something
+#[derive(Default)]
+pub struct Dead;
+
 #[derive(Default)]
 pub struct Debugging {
     pub name: Option<String>,
     pub x: Option<i16>,
     pub y: Option<i16>,
-    pub is_alive: Option<bool>,
-    pub collided: Option<bool>,
-    sprite: Option<char>,
-    width: Option<i16>,
-    height: Option<i16>,
 }
 
 impl Component for Position {}
 impl Component for Velocity {}
+impl Component for TurnQueue {}
+impl Component for Tail {}
 impl Component for Render {}
 impl Component for Collidable {}
-impl Component for Arena {}
+impl Component for Dead {}
 impl Component for Snek {}
 impl Component for Apple {}
 impl Component for Debugging {}
-impl Component for Type {}
+
+impl Tag for Collidable {}
+impl Tag for Dead {}
+impl Tag for Snek {}
+impl Tag for Apple {}
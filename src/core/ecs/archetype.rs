@@ -0,0 +1,366 @@
+use std::{any::TypeId, collections::HashMap, sync::RwLock};
+
+use super::{
+    cast_manager_mut,
+    entities::{Entity, Remap},
+    error::EcsError,
+    system::{record_borrow, Borrow},
+    Component, ComponentManager, ComponentManagerTrait, ComponentTicks, Tag, TagManager,
+};
+
+pub(crate) type Column = RwLock<Box<dyn ComponentManagerTrait>>;
+
+/// Entities that have exactly the same set of component types
+///
+/// Every column holds the components of one type, row `n` of every column belongs to
+/// `entities[n]`.
+pub struct Table {
+    type_ids: Vec<TypeId>,
+    entities: Vec<Entity>,
+    columns: HashMap<TypeId, Column>,
+}
+
+impl Table {
+    pub fn type_ids(&self) -> &[TypeId] {
+        &self.type_ids
+    }
+
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    pub(crate) fn column(&self, type_id: &TypeId) -> Option<&Column> {
+        self.columns.get(type_id)
+    }
+
+    /// Column holding the values of `T`, `None` when `T` is a tag
+    fn column_mut<T: 'static + Component>(&mut self) -> Option<&mut ComponentManager<T>> {
+        let column = self.columns.get_mut(&TypeId::of::<T>()).unwrap();
+        column.get_mut().unwrap().as_any_mut().downcast_mut()
+    }
+
+    fn push_row<T: 'static + Component>(&mut self, entity: Entity, component: T, tick: u64) {
+        let column = self.columns.get_mut(&TypeId::of::<T>()).unwrap();
+        let column = column.get_mut().unwrap().as_mut();
+        match column.as_any_mut().downcast_mut::<TagManager<T>>() {
+            Some(tags) => tags.push_row(entity),
+            None => {
+                cast_manager_mut::<T>(column).push_row(entity, component, ComponentTicks::new(tick))
+            }
+        }
+    }
+}
+
+/// Archetype storage, entities are grouped into tables by their set of component types
+///
+/// Adding or removing a component moves the entity, with all of its other components, to the
+/// table for its new set of types. Looking up a set of types walks the matching tables instead of
+/// probing a manager per type for every entity.
+#[derive(Default)]
+pub struct Archetypes {
+    prototypes: HashMap<TypeId, Box<dyn ComponentManagerTrait>>,
+    tables: Vec<Table>,
+    table_ids: HashMap<Vec<TypeId>, usize>,
+    locations: Vec<Option<(usize, usize)>>,
+}
+
+impl Archetypes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<T: 'static + Component>(&mut self) {
+        self.prototypes
+            .insert(TypeId::of::<T>(), Box::new(ComponentManager::<T>::new()));
+    }
+
+    pub fn register_tag<T: 'static + Tag>(&mut self) {
+        self.prototypes
+            .insert(TypeId::of::<T>(), Box::new(TagManager::<T>::new()));
+    }
+
+    pub fn is_registered(&self, type_id: &TypeId) -> bool {
+        self.prototypes.contains_key(type_id)
+    }
+
+    pub fn tables(&self) -> &[Table] {
+        &self.tables
+    }
+
+    /// Table and row of a living entity that has at least one component
+    pub fn location(&self, entity: Entity) -> Option<(usize, usize)> {
+        let (table, row) = (*self.locations.get(entity.index())?)?;
+        if self.tables[table].entities[row] == entity {
+            Some((table, row))
+        } else {
+            None
+        }
+    }
+
+    pub fn has(&self, entity: Entity, type_id: &TypeId) -> bool {
+        self.location(entity)
+            .is_some_and(|(table, _)| self.tables[table].type_ids.contains(type_id))
+    }
+
+    pub fn component_types(&self, entity: Entity) -> Vec<TypeId> {
+        self.location(entity)
+            .map(|(table, _)| self.tables[table].type_ids.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn ticks(
+        &self,
+        entity: Entity,
+        type_id: &TypeId,
+    ) -> Result<Option<ComponentTicks>, EcsError> {
+        let column = self
+            .location(entity)
+            .and_then(|(table, row)| Some((self.tables[table].columns.get(type_id)?, row)));
+        let (column, row) = match column {
+            Some(column) => column,
+            None => return Ok(None),
+        };
+        let name = self.prototypes[type_id].type_name();
+        match column.try_read() {
+            Ok(column) => {
+                record_borrow(Borrow::component(*type_id, name, false));
+                Ok(column.row_ticks(row))
+            }
+            Err(error) => Err(EcsError::from_lock(error, name)),
+        }
+    }
+
+    /// Entities of every table having all of `type_ids`
+    pub fn candidates(&self, type_ids: &[TypeId], dst: &mut Vec<Entity>) {
+        for table in self.tables.iter() {
+            if type_ids.iter().all(|id| table.type_ids.contains(id)) {
+                dst.extend(table.entities.iter());
+            }
+        }
+    }
+
+    pub fn insert<T: 'static + Component>(&mut self, entity: Entity, component: T, tick: u64) {
+        let type_id = TypeId::of::<T>();
+        let location = self.location(entity);
+        let mut type_ids = match location {
+            Some((table, row)) if self.tables[table].type_ids.contains(&type_id) => {
+                if let Some(column) = self.tables[table].column_mut::<T>() {
+                    column.components[row] = component;
+                    column.ticks[row].changed = tick;
+                }
+                return;
+            }
+            Some((table, _)) => self.tables[table].type_ids.clone(),
+            None => vec![],
+        };
+        type_ids.push(type_id);
+        type_ids.sort();
+
+        let to = self.table_for(type_ids);
+        self.move_entity(entity, location, to, tick);
+        self.tables[to].push_row(entity, component, tick);
+    }
+
+    /// Removes the component of type `type_id`, returns false if the entity did not have one
+    pub fn remove(&mut self, entity: Entity, type_id: &TypeId, tick: u64) -> bool {
+        let (table, row) = match self.location(entity) {
+            Some(location) if self.tables[location.0].type_ids.contains(type_id) => location,
+            _ => return false,
+        };
+        let type_ids = self.tables[table]
+            .type_ids
+            .iter()
+            .filter(|id| *id != type_id)
+            .copied()
+            .collect();
+
+        let to = self.table_for(type_ids);
+        self.move_entity(entity, Some((table, row)), to, tick);
+        true
+    }
+
+    /// Removes all components of the entity and returns their types
+    pub fn remove_entity(&mut self, entity: Entity, tick: u64) -> Vec<TypeId> {
+        let (table, row) = match self.location(entity) {
+            Some(location) => location,
+            None => return vec![],
+        };
+        for column in self.tables[table].columns.values_mut() {
+            column.get_mut().unwrap().remove_row(row, tick);
+        }
+        self.swap_remove_entity(table, row);
+        self.locations[entity.index()] = None;
+        self.tables[table].type_ids.clone()
+    }
+
+    pub fn clear_removed(&mut self, before: u64) {
+        for table in self.tables.iter_mut() {
+            for column in table.columns.values_mut() {
+                column.get_mut().unwrap().clear_removed(before);
+            }
+        }
+    }
+
+    /// Swaps the handles of moved entities in every table and drops locations past `len`
+    pub fn remap(&mut self, remap: &Remap, len: usize) {
+        for (old, new) in remap.moved() {
+            if let Some((table, row)) = self.location(*old) {
+                self.tables[table].entities[row] = *new;
+                self.locations[new.index()] = Some((table, row));
+                self.locations[old.index()] = None;
+            }
+        }
+        self.locations.truncate(len);
+        self.locations.shrink_to_fit();
+        for table in self.tables.iter_mut() {
+            for column in table.columns.values_mut() {
+                column.get_mut().unwrap().remap(remap, len);
+            }
+        }
+    }
+
+    fn table_for(&mut self, type_ids: Vec<TypeId>) -> usize {
+        if let Some(table) = self.table_ids.get(&type_ids) {
+            return *table;
+        }
+        let columns = type_ids
+            .iter()
+            .map(|type_id| {
+                // Only registered types make it into a set of types
+                (*type_id, RwLock::new(self.prototypes[type_id].new_empty()))
+            })
+            .collect();
+        self.tables.push(Table {
+            type_ids: type_ids.clone(),
+            entities: vec![],
+            columns,
+        });
+        self.table_ids.insert(type_ids, self.tables.len() - 1);
+        self.tables.len() - 1
+    }
+
+    /// Moves the entity into the table `to`, components `to` has no column for are removed
+    fn move_entity(&mut self, entity: Entity, from: Option<(usize, usize)>, to: usize, tick: u64) {
+        if let Some((from, row)) = from {
+            let (source, target) = pair_mut(&mut self.tables, from, to);
+            for (type_id, column) in source.columns.iter_mut() {
+                let column = column.get_mut().unwrap();
+                match target.columns.get_mut(type_id) {
+                    Some(target) => column.move_row(row, target.get_mut().unwrap().as_mut()),
+                    None => column.remove_row(row, tick),
+                }
+            }
+            self.swap_remove_entity(from, row);
+        }
+
+        self.tables[to].entities.push(entity);
+        if entity.index() >= self.locations.len() {
+            self.locations.resize(entity.index() + 1, None);
+        }
+        self.locations[entity.index()] = Some((to, self.tables[to].entities.len() - 1));
+    }
+
+    /// Drops the entity at `row` from the table list, the last entity takes its place just like
+    /// its components did in the columns
+    fn swap_remove_entity(&mut self, table: usize, row: usize) {
+        let entities = &mut self.tables[table].entities;
+        entities.swap_remove(row);
+        if let Some(moved) = entities.get(row) {
+            self.locations[moved.index()] = Some((table, row));
+        }
+    }
+}
+
+fn pair_mut(tables: &mut [Table], first: usize, second: usize) -> (&mut Table, &mut Table) {
+    debug_assert_ne!(first, second);
+    if first < second {
+        let (left, right) = tables.split_at_mut(second);
+        (&mut left[first], &mut right[0])
+    } else {
+        let (left, right) = tables.split_at_mut(first);
+        (&mut right[0], &mut left[second])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Position(i16);
+    struct Snek;
+
+    impl Component for Position {}
+    impl Component for Snek {}
+
+    fn setup() -> Archetypes {
+        let mut archetypes = Archetypes::new();
+        archetypes.register::<Position>();
+        archetypes.register::<Snek>();
+        archetypes
+    }
+
+    fn position(archetypes: &Archetypes, entity: Entity) -> i16 {
+        let (table, row) = archetypes.location(entity).unwrap();
+        let column = archetypes.tables[table]
+            .column(&TypeId::of::<Position>())
+            .unwrap()
+            .read()
+            .unwrap();
+        super::super::cast_manager::<Position>(column.as_ref()).components[row].0
+    }
+
+    #[test]
+    pub fn should_move_entity_between_tables() {
+        let mut archetypes = setup();
+        let first = Entity::new(0, 0);
+        let second = Entity::new(1, 0);
+        archetypes.insert(first, Position(1), 0);
+        archetypes.insert(second, Position(2), 0);
+        archetypes.insert(first, Snek, 1);
+
+        assert_eq!(archetypes.tables().len(), 2);
+        assert_eq!(archetypes.tables()[0].entities(), [second]);
+        assert_eq!(archetypes.tables()[1].entities(), [first]);
+        assert_eq!(position(&archetypes, first), 1);
+        assert_eq!(position(&archetypes, second), 2);
+        assert_eq!(
+            archetypes.ticks(first, &TypeId::of::<Position>()),
+            Ok(Some(ComponentTicks::new(0)))
+        );
+    }
+
+    #[test]
+    pub fn should_find_candidates_by_table() {
+        let mut archetypes = setup();
+        let first = Entity::new(0, 0);
+        let second = Entity::new(1, 0);
+        archetypes.insert(first, Position(1), 0);
+        archetypes.insert(first, Snek, 0);
+        archetypes.insert(second, Position(2), 0);
+
+        let mut dst = vec![];
+        archetypes.candidates(&[TypeId::of::<Snek>(), TypeId::of::<Position>()], &mut dst);
+        assert_eq!(dst, [first]);
+
+        assert!(archetypes.remove(first, &TypeId::of::<Snek>(), 1));
+        assert!(!archetypes.has(first, &TypeId::of::<Snek>()));
+        assert_eq!(position(&archetypes, first), 1);
+    }
+
+    #[test]
+    pub fn should_update_location_of_swapped_entity() {
+        let mut archetypes = setup();
+        let first = Entity::new(0, 0);
+        let second = Entity::new(1, 0);
+        archetypes.insert(first, Position(1), 0);
+        archetypes.insert(second, Position(2), 0);
+
+        assert_eq!(
+            archetypes.remove_entity(first, 1),
+            [TypeId::of::<Position>()]
+        );
+        assert!(archetypes.location(first).is_none());
+        assert_eq!(archetypes.location(second), Some((0, 0)));
+        assert_eq!(position(&archetypes, second), 2);
+    }
+}
@@ -0,0 +1,67 @@
+use super::{entities::Entity, entitymanager::EntityManager, error::EcsError, Component};
+
+/// A group of components that get added to an entity together
+///
+/// Implemented for tuples of components, so `(Position { x, y }, Collidable)` is a bundle.
+/// Named bundles implement it by handing their fields over as such a tuple.
+pub trait Bundle: Send + 'static {
+    fn insert(self, em: &mut EntityManager, entity: Entity) -> Result<(), EcsError>;
+}
+
+macro_rules! impl_bundle {
+    ($($name:ident),+) => {
+        impl<$($name: 'static + Component),+> Bundle for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn insert(self, em: &mut EntityManager, entity: Entity) -> Result<(), EcsError> {
+                let ($($name,)+) = self;
+                $(em.add_component_to_entity(entity, $name)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_bundle!(A);
+impl_bundle!(A, B);
+impl_bundle!(A, B, C);
+impl_bundle!(A, B, C, D);
+impl_bundle!(A, B, C, D, E);
+impl_bundle!(A, B, C, D, E, F);
+impl_bundle!(A, B, C, D, E, F, G);
+impl_bundle!(A, B, C, D, E, F, G, H);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Position(i16);
+    struct Collidable;
+
+    impl Component for Position {}
+    impl Component for Collidable {}
+
+    #[test]
+    pub fn should_spawn_entity_with_all_components() {
+        let mut em = EntityManager::new();
+        em.register::<Position>();
+        em.register::<Collidable>();
+        let entity = em.spawn((Position(3), Collidable)).unwrap();
+
+        assert_eq!(em.query::<&Position>().unwrap().get(entity).unwrap().0, 3);
+        assert!(em.query::<&Collidable>().unwrap().get(entity).is_some());
+    }
+
+    #[test]
+    pub fn should_not_leave_entity_behind_on_failed_spawn() {
+        let mut em = EntityManager::new();
+        em.register::<Position>();
+
+        assert_eq!(
+            em.spawn((Position(3), Collidable)),
+            Err(EcsError::UnregisteredComponent(std::any::type_name::<
+                Collidable,
+            >()))
+        );
+        assert!(!em.has_entity(Entity::new(0, 0)));
+    }
+}
@@ -0,0 +1,189 @@
+use super::{
+    bundle::Bundle, entities::Entity, entitymanager::EntityManager, error::EcsError, Component,
+};
+
+type Inserter = Box<dyn FnOnce(&mut EntityManager, Entity) -> Result<(), EcsError> + Send>;
+type Runner = Box<dyn FnOnce(&mut EntityManager) -> Result<(), EcsError> + Send>;
+
+enum Command {
+    Spawn(Vec<Inserter>),
+    Run(Runner),
+}
+
+/// Queue of structural changes made from inside a system
+///
+/// Spawning and removing entities or components while a system iterates over borrowed id lists
+/// would pull the ground from under it, so systems queue those changes here instead. The
+/// `Simulation` applies the queue in the order the commands were added once the system is done.
+/// A failing command does not stop the ones after it, its error is handed back by `apply`.
+#[derive(Default)]
+pub struct Commands {
+    queue: Vec<Command>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self { queue: vec![] }
+    }
+
+    /// Queues a new entity, components inserted on the returned builder are added to it
+    pub fn spawn(&mut self) -> SpawnCommands<'_> {
+        self.queue.push(Command::Spawn(vec![]));
+        match self.queue.last_mut() {
+            Some(Command::Spawn(inserters)) => SpawnCommands { inserters },
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn despawn(&mut self, entity: Entity) {
+        self.add(move |em| em.remove_entity(entity));
+    }
+
+    pub fn insert<T: 'static + Component>(&mut self, entity: Entity, component: T) {
+        self.add(move |em| em.add_component_to_entity(entity, component));
+    }
+
+    pub fn remove<T: 'static + Component>(&mut self, entity: Entity) {
+        self.add(move |em| em.remove_component::<T>(entity));
+    }
+
+    /// Queues any change that needs the whole `EntityManager`
+    pub fn add(
+        &mut self,
+        command: impl FnOnce(&mut EntityManager) -> Result<(), EcsError> + Send + 'static,
+    ) {
+        self.queue.push(Command::Run(Box::new(command)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Applies every queued command and returns the errors of the ones that failed
+    ///
+    /// An entity whose spawn fails is removed again together with the components it already got.
+    pub(crate) fn apply(&mut self, em: &mut EntityManager) -> Vec<EcsError> {
+        let mut errors = vec![];
+        for command in self.queue.drain(..) {
+            let result = match command {
+                Command::Spawn(inserters) => {
+                    let entity = em.create_entity();
+                    let result = inserters
+                        .into_iter()
+                        .try_for_each(|inserter| inserter(em, entity));
+                    if result.is_err() {
+                        // The entity was just created so removing it can not fail
+                        let _ = em.remove_entity(entity);
+                    }
+                    result
+                }
+                Command::Run(command) => command(em),
+            };
+            if let Err(error) = result {
+                errors.push(error);
+            }
+        }
+        errors
+    }
+}
+
+/// Builder for the components of an entity queued with `Commands::spawn`
+pub struct SpawnCommands<'a> {
+    inserters: &'a mut Vec<Inserter>,
+}
+
+impl<'a> SpawnCommands<'a> {
+    pub fn insert<T: 'static + Component>(&mut self, component: T) -> &mut Self {
+        self.inserters.push(Box::new(move |em, entity| {
+            em.add_component_to_entity(entity, component)
+        }));
+        self
+    }
+
+    pub fn insert_bundle<B: Bundle>(&mut self, bundle: B) -> &mut Self {
+        self.inserters
+            .push(Box::new(move |em, entity| bundle.insert(em, entity)));
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Segment(u8);
+
+    impl Component for Segment {}
+
+    fn setup() -> EntityManager {
+        let mut em = EntityManager::new();
+        em.register::<Segment>();
+        em
+    }
+
+    #[test]
+    pub fn should_not_change_anything_before_apply() {
+        let em = setup();
+        let mut commands = Commands::new();
+        commands.spawn().insert(Segment(1));
+
+        assert!(em
+            .borrow_components::<Segment>()
+            .unwrap()
+            .entity_ids()
+            .is_empty());
+    }
+
+    #[test]
+    pub fn should_apply_commands_in_order() {
+        let mut em = setup();
+        let entity = em.create_entity();
+        em.add_component_to_entity(entity, Segment(1)).unwrap();
+        let mut commands = Commands::new();
+        commands.despawn(entity);
+        commands.spawn().insert(Segment(2));
+        assert!(commands.apply(&mut em).is_empty());
+
+        let spawned = em.borrow_components::<Segment>().unwrap().entity_ids()[0];
+        assert!(!em.has_entity(entity));
+        assert_eq!(spawned.index(), entity.index());
+        assert_eq!(em.query::<&Segment>().unwrap().get(spawned).unwrap().0, 2);
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    pub fn should_insert_and_remove_components() {
+        let mut em = setup();
+        let entity = em.create_entity();
+        let mut commands = Commands::new();
+        commands.insert(entity, Segment(1));
+        commands.apply(&mut em);
+
+        assert!(em.query::<&Segment>().unwrap().get(entity).is_some());
+
+        commands.remove::<Segment>(entity);
+        commands.apply(&mut em);
+
+        assert!(em.query::<&Segment>().unwrap().get(entity).is_none());
+        assert!(em.has_entity(entity));
+    }
+
+    #[test]
+    pub fn should_keep_applying_after_failed_command() {
+        let mut em = setup();
+        let entity = em.create_entity();
+        let mut commands = Commands::new();
+        commands.despawn(entity);
+        commands.despawn(entity);
+        commands.spawn().insert(Segment(1));
+
+        assert_eq!(commands.apply(&mut em), [EcsError::StaleEntity(entity)]);
+        assert_eq!(
+            em.borrow_components::<Segment>()
+                .unwrap()
+                .entity_ids()
+                .len(),
+            1
+        );
+    }
+}
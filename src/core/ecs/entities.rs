@@ -1,30 +1,138 @@
-#[derive(Debug)]
+use super::error::EcsError;
+
+/// Handle to an entity
+///
+/// The index points at a slot in `Entities` and the generation tells which incarnation of that
+/// slot the handle was created for. When an entity is removed the generation of its slot is
+/// bumped, so any handle still pointing at the old incarnation is considered stale and will not
+/// resolve to whatever entity gets spawned into that slot next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Entity {
-    alive: bool,
+    index: usize,
+    generation: u32,
 }
 
 impl Entity {
-    pub fn new() -> Entity {
-        Self { alive: true }
+    pub(crate) fn new(index: usize, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
     }
 
-    pub fn is_alive(&self) -> bool {
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[derive(Default, Debug)]
+struct EntitySlot {
+    alive: bool,
+    generation: u32,
+    killed_at: u64,
+}
+
+impl EntitySlot {
+    fn is_alive(&self) -> bool {
         self.alive
     }
 
-    pub fn kill(&mut self) {
+    fn kill(&mut self, tick: u64) {
         self.alive = false;
+        self.generation += 1;
+        self.killed_at = tick;
     }
 
-    pub fn resurrect(&mut self) {
+    fn resurrect(&mut self) {
         self.alive = true;
     }
 }
 
+/// Settings for `EntityManager::compact`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// Updates a slot has to stay dead before compaction reclaims it, slots that were freed more
+    /// recently are likely to be reused soon and are kept
+    pub min_idle: u64,
+}
+
+/// Report of a compaction, maps the handles of moved entities to their new handles
+///
+/// Old handles of moved entities are stale after the compaction. Components and resources that
+/// hold handles implement `RemapEntities` and are registered with `EntityManager::remap_component`
+/// or `EntityManager::remap_resource`, so the compaction swaps their handles itself. Handles kept
+/// anywhere else have to be swapped through `remap` by whoever holds them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Remap {
+    moved: Vec<(Entity, Entity)>,
+    reclaimed: usize,
+}
+
+impl Remap {
+    /// New handle of a moved entity
+    pub fn get(&self, entity: Entity) -> Option<Entity> {
+        let index = self
+            .moved
+            .binary_search_by_key(&entity.index, |(old, _)| old.index)
+            .ok()?;
+        let (old, new) = self.moved[index];
+        (old == entity).then_some(new)
+    }
+
+    /// New handle of the entity if it was moved, otherwise the handle itself
+    pub fn remap(&self, entity: Entity) -> Entity {
+        self.get(entity).unwrap_or(entity)
+    }
+
+    /// Old and new handles of all moved entities, ordered by old index
+    pub fn moved(&self) -> &[(Entity, Entity)] {
+        &self.moved
+    }
+
+    /// Number of slots the pool shrunk by
+    pub fn reclaimed(&self) -> usize {
+        self.reclaimed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moved.is_empty() && self.reclaimed == 0
+    }
+}
+
+/// Data holding entity handles that has to follow entities moved by a compaction
+pub trait RemapEntities {
+    /// Swaps every moved handle for its new handle
+    fn remap_entities(&mut self, remap: &Remap);
+}
+
+impl RemapEntities for Entity {
+    fn remap_entities(&mut self, remap: &Remap) {
+        *self = remap.remap(*self);
+    }
+}
+
+impl<T: RemapEntities> RemapEntities for Option<T> {
+    fn remap_entities(&mut self, remap: &Remap) {
+        if let Some(value) = self {
+            value.remap_entities(remap);
+        }
+    }
+}
+
+impl<T: RemapEntities> RemapEntities for Vec<T> {
+    fn remap_entities(&mut self, remap: &Remap) {
+        for value in self.iter_mut() {
+            value.remap_entities(remap);
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Entities {
-    pub entities: Vec<Entity>,
+    entities: Vec<EntitySlot>,
     available: Vec<usize>,
+    first_generation: u32,
 }
 
 /// Struct that holds entities
@@ -34,40 +142,196 @@ pub struct Entities {
 /// resurrected when a new entity is spawned. This will make sure that we dont do a lot of
 /// unneccasary allocations of new entities when they are killed/removed.
 ///
-/// TODO: maybe look into some sort of garbage collection system that will systematically remove
-/// killed off entities that dont get respawned in a certain amount of time/frames.
+/// Killing an entity bumps the generation of its slot, so the resurrected entity gets a new
+/// handle and handles to the killed entity no longer pass `has`.
+///
+/// Slots that stay dead for a while are reclaimed by `compact`, which moves living entities from
+/// the end of the pool into them and shrinks the pool.
 impl Entities {
     pub fn new() -> Self {
         Self {
             entities: vec![],
             available: vec![],
+            first_generation: 0,
         }
     }
 
-    fn push(&self, _entity: Entity) -> usize {
-        todo!()
-    }
-
-    pub fn create(&mut self) -> usize {
+    pub fn create(&mut self) -> Entity {
         if !self.available.is_empty() {
             let index = self.available.remove(0);
-            self.entities[index].resurrect();
-            return index;
+            let slot = &mut self.entities[index];
+            slot.resurrect();
+            return Entity::new(index, slot.generation);
         }
-        let entity = Entity::new();
-        self.entities.push(entity);
-        self.entities.len() - 1
+        self.entities.push(EntitySlot {
+            alive: true,
+            generation: self.first_generation,
+            killed_at: 0,
+        });
+        Entity::new(self.entities.len() - 1, self.first_generation)
     }
 
-    pub fn remove(&mut self, entity_id: usize) {
-        if !self.has(entity_id) {
+    /// Kills the entity, `tick` is remembered to tell how long its slot has been dead
+    pub fn remove(&mut self, entity: Entity, tick: u64) {
+        if !self.has(entity) {
             return;
         }
-        self.entities[entity_id].kill();
-        self.available.push(entity_id);
+        self.entities[entity.index].kill(tick);
+        self.available.push(entity.index);
+    }
+
+    /// Number of slots in the pool, dead ones included
+    pub fn slot_count(&self) -> usize {
+        self.entities.len()
+    }
+
+    /// Moves living entities from the end of the pool into slots that have been dead for at least
+    /// `min_idle` ticks at `tick`, then drops the dead slots at the end of the pool
+    ///
+    /// A slot that died more recently stays where it is, so the pool only shrinks down to the
+    /// last living entity or recently freed slot. Slots past the end of the pool start at a
+    /// generation above any dropped slot, so handles into dropped slots stay stale once the pool
+    /// grows again.
+    pub fn compact(&mut self, min_idle: u64, tick: u64) -> Remap {
+        let reclaimable =
+            |slot: &EntitySlot| !slot.alive && tick.saturating_sub(slot.killed_at) >= min_idle;
+        let mut moved = vec![];
+        let mut hole = 0;
+        let mut end = self.entities.len();
+        loop {
+            while end > 0 && reclaimable(&self.entities[end - 1]) {
+                end -= 1;
+            }
+            while hole < end && !reclaimable(&self.entities[hole]) {
+                hole += 1;
+            }
+            if hole >= end || !self.entities[end - 1].alive {
+                break;
+            }
+            let from = end - 1;
+            let old = Entity::new(from, self.entities[from].generation);
+            let new = Entity::new(hole, self.entities[hole].generation);
+            self.entities[hole].resurrect();
+            self.entities[from].kill(tick);
+            moved.push((old, new));
+            end -= 1;
+        }
+        moved.reverse();
+
+        let len = self.entities.len();
+        for slot in self.entities.drain(end..) {
+            self.first_generation = self.first_generation.max(slot.generation);
+        }
+        let entities = &self.entities;
+        self.available
+            .retain(|index| entities.get(*index).is_some_and(|slot| !slot.alive));
+
+        Remap {
+            moved,
+            reclaimed: len - self.entities.len(),
+        }
+    }
+
+    pub fn has(&self, entity: Entity) -> bool {
+        self.check(entity).is_ok()
     }
 
-    pub fn has(&self, entity_id: usize) -> bool {
-        entity_id < self.entities.len() && self.entities[entity_id].is_alive()
+    /// Tells apart handles that were never handed out from handles to removed entities
+    pub fn check(&self, entity: Entity) -> Result<(), EcsError> {
+        match self.entities.get(entity.index) {
+            Some(slot) if slot.is_alive() && slot.generation == entity.generation => Ok(()),
+            Some(_) => Err(EcsError::StaleEntity(entity)),
+            None => Err(EcsError::NoSuchEntity(entity)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn should_reuse_index_with_new_generation() {
+        let mut entities = Entities::new();
+        let first = entities.create();
+        entities.remove(first, 0);
+        let second = entities.create();
+
+        assert_eq!(first.index(), second.index());
+        assert_ne!(first.generation(), second.generation());
+    }
+
+    #[test]
+    pub fn should_not_have_stale_handle() {
+        let mut entities = Entities::new();
+        let first = entities.create();
+        entities.remove(first, 0);
+        let second = entities.create();
+
+        assert!(!entities.has(first));
+        assert!(entities.has(second));
+        assert_eq!(entities.check(first), Err(EcsError::StaleEntity(first)));
+        assert_eq!(
+            entities.check(Entity::new(5, 0)),
+            Err(EcsError::NoSuchEntity(Entity::new(5, 0)))
+        );
+    }
+
+    #[test]
+    pub fn should_ignore_removal_of_stale_handle() {
+        let mut entities = Entities::new();
+        let first = entities.create();
+        entities.remove(first, 0);
+        let second = entities.create();
+        entities.remove(first, 0);
+
+        assert!(entities.has(second));
+    }
+
+    #[test]
+    pub fn should_move_living_entities_into_idle_slots() {
+        let mut entities = Entities::new();
+        let handles = (0..5).map(|_| entities.create()).collect::<Vec<_>>();
+        entities.remove(handles[0], 1);
+        entities.remove(handles[2], 9);
+        entities.remove(handles[3], 1);
+
+        let remap = entities.compact(5, 10);
+
+        assert_eq!(entities.slot_count(), 3);
+        assert_eq!(remap.reclaimed(), 2);
+        assert_eq!(remap.moved().len(), 1);
+        let moved = remap.get(handles[4]).unwrap();
+        assert_eq!(moved.index(), 0);
+        assert!(entities.has(moved));
+        assert_eq!(remap.remap(handles[1]), handles[1]);
+        assert!(!entities.has(handles[0]));
+        assert!(!entities.has(handles[4]));
+
+        assert_eq!(entities.create().index(), 2);
+        assert_eq!(entities.create().index(), 3);
+        assert!(!entities.has(handles[2]));
+        assert!(!entities.has(handles[3]));
+    }
+
+    #[test]
+    pub fn should_keep_recently_freed_slot_at_end_of_pool() {
+        let mut entities = Entities::new();
+        let handles = (0..5).map(|_| entities.create()).collect::<Vec<_>>();
+        entities.remove(handles[1], 1);
+        entities.remove(handles[4], 9);
+
+        let remap = entities.compact(5, 10);
+
+        assert_eq!(entities.slot_count(), 5);
+        assert_eq!(remap.reclaimed(), 0);
+        assert!(remap.moved().is_empty());
+        assert!(entities.has(handles[3]));
+
+        let remap = entities.compact(5, 14);
+
+        assert_eq!(entities.slot_count(), 3);
+        assert_eq!(remap.reclaimed(), 2);
+        assert_eq!(remap.get(handles[3]).map(|moved| moved.index()), Some(1));
     }
 }
@@ -3,12 +3,27 @@ use std::{
     collections::{hash_map::Entry, HashMap},
 };
 
-use super::{entitymanager::EntityManager, Component};
+use super::{
+    entities::Entity,
+    entitymanager::EntityManager,
+    error::EcsError,
+    filter::{ComponentSet, Filter},
+    Component,
+};
 
+/// Cached lists of entity ids matching a set of components
+///
+/// A cached list is rebuilt when any component type involved in it, filters included, has been
+/// updated after the frame the list was built in. Lists filtered on `Added` or `Changed` are
+/// rebuilt once per frame since writes to components do not count as updates of the type.
+///
+/// Every system owns an accessor, the `Schedule` records in it when the system last ran so change
+/// filters only see what happened since then.
 #[derive(Default)]
 pub struct EntityIdAccessor {
-    cache_map: HashMap<TypeId, Vec<usize>>,
+    cache_map: HashMap<TypeId, Vec<Entity>>,
     updated_frame_map: HashMap<TypeId, u64>,
+    last_run: u64,
 }
 
 impl EntityIdAccessor {
@@ -16,60 +31,285 @@ impl EntityIdAccessor {
         EntityIdAccessor {
             cache_map: HashMap::new(),
             updated_frame_map: HashMap::new(),
+            last_run: 0,
         }
     }
 
+    pub(crate) fn set_last_run(&mut self, tick: u64) {
+        self.last_run = tick;
+    }
+
     pub fn borrow_ids<T1: 'static + Component>(
         &mut self,
         manager: &EntityManager,
-    ) -> Option<&Vec<usize>> {
-        let type_id = TypeId::of::<T1>();
-        let needs_updating = if let Entry::Vacant(e) = self.cache_map.entry(type_id) {
-            e.insert(Vec::new());
-            true
-        } else {
-            let update_frame = *self.updated_frame_map.get(&type_id).unwrap();
-            manager.get_update_frame::<T1>() != update_frame
-        };
-
-        if needs_updating {
-            let src = &manager.borrow_entity_ids::<T1>().unwrap();
-            let dst = self.cache_map.get_mut(&type_id).unwrap();
-            dst.clear();
-            for id in src.iter() {
-                dst.push(*id);
-            }
-            self.updated_frame_map.insert(type_id, manager.get_frame());
-        }
-        self.cache_map.get(&type_id)
+    ) -> Result<&Vec<Entity>, EcsError> {
+        self.borrow_ids_filtered::<T1, ()>(manager)
     }
 
     pub fn borrow_ids_for_pair<T1: 'static + Component, T2: 'static + Component>(
         &mut self,
         manager: &EntityManager,
-    ) -> Option<&Vec<usize>> {
-        let type_id = TypeId::of::<(T1, T2)>();
+    ) -> Result<&Vec<Entity>, EcsError> {
+        self.borrow_ids_filtered::<(T1, T2), ()>(manager)
+    }
+
+    /// Ids of all entities having every component in `C`, for example `(Position, Render)`
+    pub fn borrow_ids_for<C: ComponentSet>(
+        &mut self,
+        manager: &EntityManager,
+    ) -> Result<&Vec<Entity>, EcsError> {
+        self.borrow_ids_filtered::<C, ()>(manager)
+    }
+
+    /// Ids of all entities having every component in `C` that also pass the filter `F`
+    ///
+    /// `F` is a `With<T>`, a `Without<T>`, an `Added<T>`, a `Changed<T>` or a tuple of those, so
+    /// everything collidable that is not the snake is
+    /// `borrow_ids_filtered::<(Collidable, Position), Without<Snek>>`.
+    ///
+    /// Fails if a component in `C` or `F` is not registered or is borrowed mutably by a live
+    /// query.
+    pub fn borrow_ids_filtered<C: ComponentSet, F: Filter>(
+        &mut self,
+        manager: &EntityManager,
+    ) -> Result<&Vec<Entity>, EcsError> {
+        C::check(manager)?;
+        F::check(manager)?;
+        let type_id = TypeId::of::<(C, F)>();
         let needs_updating = if let Entry::Vacant(e) = self.cache_map.entry(type_id) {
             e.insert(Vec::new());
             true
         } else {
             let update_frame = *self.updated_frame_map.get(&type_id).unwrap();
-            manager.get_update_frame::<T1>() != update_frame
-                || manager.get_update_frame::<T2>() != update_frame
+            let mut type_ids = vec![];
+            C::type_ids(&mut type_ids);
+            F::type_ids(&mut type_ids);
+            (F::tracks_changes() && update_frame != manager.get_frame())
+                || type_ids
+                    .iter()
+                    .any(|id| manager.get_update_frame_of(id) > update_frame)
         };
 
         if needs_updating {
-            let src = &manager.borrow_entity_ids::<T1>().unwrap();
-            let manager_t2 = manager.borrow_component_manager::<T2>();
             let dst = self.cache_map.get_mut(&type_id).unwrap();
-            dst.clear();
-            for id in src.iter() {
-                if manager_t2.has(*id) {
-                    dst.push(*id);
-                }
+            if let Err(error) = Self::collect::<C, F>(manager, self.last_run, dst) {
+                // Forget the partial list so the next call starts over
+                self.cache_map.remove(&type_id);
+                self.updated_frame_map.remove(&type_id);
+                return Err(error);
             }
             self.updated_frame_map.insert(type_id, manager.get_frame());
         }
-        self.cache_map.get(&type_id)
+        Ok(&self.cache_map[&type_id])
+    }
+
+    fn collect<C: ComponentSet, F: Filter>(
+        manager: &EntityManager,
+        since: u64,
+        dst: &mut Vec<Entity>,
+    ) -> Result<(), EcsError> {
+        let mut candidates = vec![];
+        let mut type_ids = vec![];
+        C::type_ids(&mut type_ids);
+        manager.candidates(&type_ids, &mut candidates)?;
+        dst.clear();
+        for id in candidates {
+            if C::matches(manager, id)? && F::matches(manager, id, since)? {
+                dst.push(id);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::ecs::{
+        filter::{Added, Changed, With, Without},
+        storage::StorageMode,
+        Tag,
+    };
+
+    struct Position;
+    struct Collidable;
+    struct Snek;
+
+    impl Component for Position {}
+    impl Component for Collidable {}
+    impl Component for Snek {}
+
+    fn setup() -> (EntityManager, Entity, Entity) {
+        setup_with(StorageMode::SparseSet)
+    }
+
+    fn setup_with(mode: StorageMode) -> (EntityManager, Entity, Entity) {
+        let mut em = EntityManager::with_storage(mode);
+        em.register::<Position>();
+        em.register::<Collidable>();
+        em.register::<Snek>();
+        let snek = em.spawn((Position, Collidable, Snek)).unwrap();
+        let wall = em.spawn((Position, Collidable)).unwrap();
+        (em, snek, wall)
+    }
+
+    #[test]
+    pub fn should_filter_with_and_without() {
+        let (em, snek, wall) = setup();
+        let mut eia = EntityIdAccessor::new();
+
+        assert_eq!(
+            eia.borrow_ids_filtered::<(Collidable, Position), Without<Snek>>(&em)
+                .unwrap(),
+            &vec![wall]
+        );
+        assert_eq!(
+            eia.borrow_ids_filtered::<Position, With<Snek>>(&em)
+                .unwrap(),
+            &vec![snek]
+        );
+    }
+
+    #[test]
+    pub fn should_invalidate_when_filter_type_changes() {
+        let (mut em, _, wall) = setup();
+        let mut eia = EntityIdAccessor::new();
+        eia.borrow_ids_filtered::<(Collidable, Position), Without<Snek>>(&em)
+            .unwrap();
+
+        em.step_frame();
+        em.add_component_to_entity(wall, Snek).unwrap();
+        em.step_frame();
+
+        assert!(eia
+            .borrow_ids_filtered::<(Collidable, Position), Without<Snek>>(&em)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    pub fn should_invalidate_when_entity_is_removed() {
+        let (mut em, snek, wall) = setup();
+        let mut eia = EntityIdAccessor::new();
+        eia.borrow_ids_for::<(Position, Collidable)>(&em).unwrap();
+
+        em.remove_entity(snek).unwrap();
+
+        assert_eq!(
+            eia.borrow_ids_for::<(Position, Collidable)>(&em).unwrap(),
+            &vec![wall]
+        );
+    }
+
+    #[test]
+    pub fn should_only_see_changes_since_last_run() {
+        let (mut em, snek, wall) = setup();
+        let mut eia = EntityIdAccessor::new();
+
+        assert_eq!(
+            eia.borrow_ids_filtered::<Position, Added<Position>>(&em)
+                .unwrap(),
+            &vec![snek, wall]
+        );
+
+        eia.set_last_run(em.change_tick());
+        em.step_frame();
+        *em.query::<&mut Position>().unwrap().get_mut(wall).unwrap() = Position;
+
+        assert!(eia
+            .borrow_ids_filtered::<Position, Added<Position>>(&em)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            eia.borrow_ids_filtered::<Position, Changed<Position>>(&em)
+                .unwrap(),
+            &vec![wall]
+        );
+    }
+
+    #[test]
+    pub fn should_filter_in_archetype_storage() {
+        let (mut em, snek, wall) = setup_with(StorageMode::Archetype);
+        let mut eia = EntityIdAccessor::new();
+
+        assert_eq!(
+            eia.borrow_ids_filtered::<(Collidable, Position), Without<Snek>>(&em)
+                .unwrap(),
+            &vec![wall]
+        );
+
+        em.step_frame();
+        em.remove_component::<Snek>(snek).unwrap();
+        em.step_frame();
+
+        assert_eq!(
+            eia.borrow_ids_filtered::<(Collidable, Position), Without<Snek>>(&em)
+                .unwrap(),
+            &vec![wall, snek]
+        );
+    }
+
+    #[test]
+    pub fn should_filter_on_tags() {
+        #[derive(Default)]
+        struct Frozen;
+        impl Component for Frozen {}
+        impl Tag for Frozen {}
+
+        let (mut em, snek, wall) = setup();
+        em.register_tag::<Frozen>();
+        em.add_tag::<Frozen>(wall).unwrap();
+        let mut eia = EntityIdAccessor::new();
+
+        assert_eq!(
+            eia.borrow_ids_filtered::<Position, With<Frozen>>(&em)
+                .unwrap(),
+            &vec![wall]
+        );
+        assert_eq!(
+            eia.borrow_ids_filtered::<Position, Without<Frozen>>(&em)
+                .unwrap(),
+            &vec![snek]
+        );
+    }
+
+    #[test]
+    pub fn should_move_tags_between_tables() {
+        #[derive(Default)]
+        struct Frozen;
+        impl Component for Frozen {}
+        impl Tag for Frozen {}
+
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (mut em, snek, wall) = setup_with(mode);
+            em.register_tag::<Frozen>();
+            em.add_tag::<Frozen>(snek).unwrap();
+            em.add_tag::<Frozen>(wall).unwrap();
+            em.remove_component::<Snek>(snek).unwrap();
+            em.remove_component::<Frozen>(wall).unwrap();
+            let mut eia = EntityIdAccessor::new();
+
+            assert_eq!(
+                eia.borrow_ids_filtered::<Position, With<Frozen>>(&em)
+                    .unwrap(),
+                &vec![snek]
+            );
+            assert_eq!(
+                em.query::<&Frozen>().err(),
+                Some(EcsError::FetchedTag(std::any::type_name::<Frozen>()))
+            );
+        }
+    }
+
+    #[test]
+    pub fn should_fail_while_component_is_borrowed_mutably() {
+        let (em, _, _) = setup();
+        let mut eia = EntityIdAccessor::new();
+        let _positions = em.query::<&mut Position>().unwrap();
+
+        assert_eq!(
+            eia.borrow_ids_for::<(Collidable, Position)>(&em),
+            Err(EcsError::AliasingBorrow(std::any::type_name::<Position>()))
+        );
     }
 }
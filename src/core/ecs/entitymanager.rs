@@ -1,214 +1,576 @@
-use std::{any::TypeId, collections::HashMap, mem::transmute};
-
-use super::{entities::Entities, Component, ComponentManager, ComponentManagerTrait};
-
+use std::{
+    any::{type_name, TypeId},
+    collections::HashMap,
+    mem,
+    sync::Mutex,
+};
+
+use super::{
+    bundle::Bundle,
+    command::Commands,
+    entities::{Compaction, Entities, Entity, Remap, RemapEntities},
+    error::EcsError,
+    event::{EventWriter, Events},
+    hook::Hooks,
+    query::{Query, QueryData},
+    resource::{Res, ResMut, Resources},
+    storage::{ComponentsRef, ComponentsRefMut, Storage, StorageMode},
+    Component, ComponentTicks, Tag,
+};
+
+/// Owns all entities, the storage holding their components and the resources
+///
+/// Every component manager sits behind a `RwLock` so queries can borrow several managers at
+/// once through a shared reference, also from systems running in parallel. Conflicting borrows
+/// are caught at runtime and returned as `EcsError::AliasingBorrow` instead of blocking or
+/// handing out aliasing references.
 #[derive(Default)]
 pub struct EntityManager {
     pub entities: Entities,
-    pub manager_map: HashMap<TypeId, Box<dyn ComponentManagerTrait>>,
+    storage: Storage,
+    resources: Resources,
     frame: u64,
+    updates: u64,
     last_updated_map: HashMap<TypeId, u64>,
+    hooks: Hooks,
+    hook_commands: Mutex<Commands>,
+    remappers: Vec<fn(&EntityManager, &Remap)>,
 }
 
 impl EntityManager {
     pub fn new() -> Self {
+        Self::with_storage(StorageMode::SparseSet)
+    }
+
+    pub fn with_storage(mode: StorageMode) -> Self {
         Self {
             entities: Entities::new(),
-            manager_map: HashMap::new(),
+            storage: Storage::new(mode),
+            resources: Resources::new(),
             frame: 0,
+            updates: 0,
             last_updated_map: HashMap::new(),
+            hooks: Hooks::default(),
+            hook_commands: Mutex::new(Commands::new()),
+            remappers: vec![],
         }
     }
 
-    pub fn create_entity(&mut self) -> usize {
+    pub fn storage_mode(&self) -> StorageMode {
+        self.storage.mode()
+    }
+
+    pub fn create_entity(&mut self) -> Entity {
         self.entities.create()
     }
 
-    pub fn remove_entity(&mut self, entity_id: usize) {
-        let frame = self.get_frame();
-        for (_, manager) in self.manager_map.iter_mut() {
-            if manager.has(entity_id) {
-                manager.remove(entity_id);
-                // @TODO: Write comment for +1
-                self.last_updated_map
-                    .insert(manager.get_type_id(), frame + 1);
+    /// Creates an entity with every component in `bundle`, nothing is left behind on failure
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) -> Result<Entity, EcsError> {
+        let entity = self.create_entity();
+        match bundle.insert(self, entity) {
+            Ok(()) => Ok(entity),
+            Err(error) => {
+                self.remove_entity(entity)?;
+                Err(error)
             }
         }
-        self.entities.remove(entity_id);
     }
 
-    pub fn get_last_updated_frame<T: 'static + Component>(&self) -> u64 {
-        let type_id = TypeId::of::<T>();
-        *self.last_updated_map.get(&type_id).unwrap()
+    pub fn has_entity(&self, entity: Entity) -> bool {
+        self.entities.has(entity)
+    }
+
+    pub fn remove_entity(&mut self, entity: Entity) -> Result<(), EcsError> {
+        self.entities.check(entity)?;
+        self.hooks
+            .run_on_despawn(self, entity, &mut self.hook_commands.lock().unwrap())?;
+        let tick = self.change_tick();
+        for type_id in self.storage.remove_entity(entity, tick) {
+            self.last_updated_map.insert(type_id, tick);
+        }
+        self.entities.remove(entity, self.updates);
+        Ok(())
+    }
+
+    /// Reclaims entity slots that have been dead for at least `compaction.min_idle` updates
+    ///
+    /// Living entities at the end of the pool are moved into the reclaimed slots and the
+    /// component storage shrinks along with the pool. Moved entities get new handles, their old
+    /// handles turn stale and the returned `Remap` tells which handle became which. Cached id
+    /// lists are rebuilt on their next use.
+    ///
+    /// Handles held in components and resources registered with `remap_component` and
+    /// `remap_resource` are swapped to the new handles before this returns. Every other handle
+    /// held outside the `EntityManager`, in systems or in the caller, is invalid afterwards
+    /// unless it is passed through the returned `Remap`.
+    pub fn compact(&mut self, compaction: Compaction) -> Remap {
+        let tick = self.change_tick();
+        let remap = self.entities.compact(compaction.min_idle, self.updates);
+        self.storage.remap(&remap, self.entities.slot_count());
+        if !remap.moved().is_empty() {
+            for frame in self.last_updated_map.values_mut() {
+                *frame = tick;
+            }
+            for remapper in self.remappers.iter() {
+                remapper(self, &remap);
+            }
+        }
+        remap
     }
 
+    /// Makes compactions swap the entity handles held by every `T`
+    pub fn remap_component<T: 'static + Component + RemapEntities>(&mut self) -> &mut Self {
+        self.remappers.push(remap_components::<T>);
+        self
+    }
+
+    /// Makes compactions swap the entity handles held by the resource `T`, `Events<E>` included
+    pub fn remap_resource<T: 'static + Send + Sync + RemapEntities>(&mut self) -> &mut Self {
+        self.remappers.push(remap_resource::<T>);
+        self
+    }
+
+    pub fn get_last_updated_frame<T: 'static + Component>(&self) -> Result<u64, EcsError> {
+        match self.last_updated_map.get(&TypeId::of::<T>()) {
+            Some(frame) => Ok(*frame),
+            None => Err(EcsError::UnregisteredComponent(type_name::<T>())),
+        }
+    }
+
+    /// Adds a component to a living entity, replacing the one it already had
     pub fn add_component_to_entity<T: 'static + Component>(
         &mut self,
-        entity_id: usize,
+        entity: Entity,
         component: T,
+    ) -> Result<(), EcsError> {
+        self.entities.check(entity)?;
+        let type_id = TypeId::of::<T>();
+        let added = !self.storage.has(entity, &type_id)?;
+        let tick = self.change_tick();
+        self.storage.insert(entity, component, tick)?;
+        self.last_updated_map.insert(type_id, tick);
+        if added {
+            self.hooks.run_on_add(
+                self,
+                entity,
+                &type_id,
+                &mut self.hook_commands.lock().unwrap(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Marks a living entity with the tag `T`
+    pub fn add_tag<T: 'static + Tag>(&mut self, entity: Entity) -> Result<(), EcsError> {
+        self.add_component_to_entity(entity, T::default())
+    }
+
+    /// Removes the `T` of an entity, the entity itself stays alive
+    pub fn remove_component<T: 'static + Component>(
+        &mut self,
+        entity: Entity,
+    ) -> Result<(), EcsError> {
+        self.entities.check(entity)?;
+        self.storage.check_registered::<T>()?;
+        let type_id = TypeId::of::<T>();
+        if self.storage.has(entity, &type_id)? {
+            self.hooks.run_on_remove(
+                self,
+                entity,
+                &type_id,
+                &mut self.hook_commands.lock().unwrap(),
+            );
+        }
+        let tick = self.change_tick();
+        if self.storage.remove::<T>(entity, tick)? {
+            self.last_updated_map.insert(type_id, tick);
+        }
+        Ok(())
+    }
+
+    /// Whether a living entity has a `T`, fails if the `T`s are borrowed mutably
+    pub fn has_component<T: 'static + Component>(&self, entity: Entity) -> Result<bool, EcsError> {
+        match self.entities.has(entity) {
+            true => self.storage.has(entity, &TypeId::of::<T>()),
+            false => Ok(false),
+        }
+    }
+
+    /// Types of all components of an entity, in no particular order
+    pub fn component_types(&self, entity: Entity) -> Result<Vec<TypeId>, EcsError> {
+        self.entities.check(entity)?;
+        self.storage.component_types(entity)
+    }
+
+    /// Runs `hook` whenever a `T` is added to an entity that did not have one yet
+    ///
+    /// Hooks of the same kind run in the order they were registered, see `Hooks`.
+    pub fn on_add<T: 'static + Component>(
+        &mut self,
+        hook: impl Fn(&EntityManager, Entity, &mut Commands) + Send + Sync + 'static,
     ) -> &mut Self {
-        let component_manager = self.borrow_component_manager_mut::<T>();
-        component_manager.add(entity_id, component);
+        self.hooks.add_on_add(TypeId::of::<T>(), Box::new(hook));
         self
     }
 
-    pub(crate) fn register<T: 'static + Component>(&mut self) -> &mut Self {
-        // @TODO: Check if component manager for T already exists
-        let type_id = TypeId::of::<T>();
-        self.manager_map
-            .insert(type_id, Box::new(ComponentManager::<T>::new()));
-        self.last_updated_map.insert(type_id, self.frame);
+    /// Runs `hook` right before the `T` of an entity is removed, also when the entity is removed
+    pub fn on_remove<T: 'static + Component>(
+        &mut self,
+        hook: impl Fn(&EntityManager, Entity, &mut Commands) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.hooks.add_on_remove(TypeId::of::<T>(), Box::new(hook));
         self
     }
 
-    pub(crate) fn borrow_entity_ids<T: 'static + Component>(&self) -> Option<&Vec<usize>> {
-        Some(self.borrow_component_manager::<T>().borrow_entity_ids())
+    /// Creates a query over the components in `Q`, for example `(&Velocity, &mut Position)`
+    ///
+    /// Fails if a component in `Q` is not registered, if `Q` borrows a component mutably more
+    /// than once or if a component in `Q` is already borrowed by another live query in a
+    /// conflicting way.
+    pub fn query<Q: QueryData>(&self) -> Result<Query<'_, Q>, EcsError> {
+        Query::new(self)
     }
 
-    fn borrow_component_manager_mut<T: 'static + Component>(&mut self) -> &mut ComponentManager<T> {
-        let type_id = TypeId::of::<T>();
+    /// Inserts a resource, replacing any previous resource of the same type
+    pub fn insert_resource<T: 'static + Send + Sync>(&mut self, resource: T) -> &mut Self {
+        self.resources.insert(resource);
+        self
+    }
 
-        // Handle if there is no manager for a component
-        let unknown = self.manager_map.get_mut(&type_id).unwrap().as_mut();
-        let manager = cast_manager_mut(unknown);
-        manager
+    pub fn remove_resource<T: 'static + Send + Sync>(&mut self) -> Option<T> {
+        self.resources.remove::<T>()
     }
 
-    pub(crate) fn borrow_component_manager<T: 'static + Component>(&self) -> &ComponentManager<T> {
-        let type_id = TypeId::of::<T>();
+    pub fn has_resource<T: 'static>(&self) -> bool {
+        self.resources.contains::<T>()
+    }
 
-        // Handle if there is no manager for a component
-        let unknown = self.manager_map.get(&type_id).unwrap().as_ref();
-        let manager = cast_manager(unknown);
-        manager
+    /// Shared borrow of the resource `T`, fails if it is missing or borrowed mutably
+    pub fn resource<T: 'static + Send + Sync>(&self) -> Result<Res<'_, T>, EcsError> {
+        self.resources.get::<T>()
     }
 
-    pub(crate) fn get_update_frame<T: 'static + Component>(&self) -> u64 {
-        let type_id = TypeId::of::<T>();
-        *self.last_updated_map.get(&type_id).unwrap()
+    /// Exclusive borrow of the resource `T`, fails if it is missing or borrowed at all
+    pub fn resource_mut<T: 'static + Send + Sync>(&self) -> Result<ResMut<'_, T>, EcsError> {
+        self.resources.get_mut::<T>()
     }
 
-    pub(crate) fn get_frame(&self) -> u64 {
-        self.frame
+    pub fn events<T: 'static + Send + Sync>(&self) -> Result<Res<'_, Events<T>>, EcsError> {
+        self.resources.get::<Events<T>>()
     }
 
-    pub(crate) fn borrow_component_pair_mut<T1: 'static + Component, T2: 'static + Component>(
+    pub fn event_writer<T: 'static + Send + Sync>(&self) -> Result<EventWriter<'_, T>, EcsError> {
+        self.resources.get_mut::<Events<T>>().map(EventWriter::new)
+    }
+
+    pub(crate) fn register<T: 'static + Component>(&mut self) -> &mut Self {
+        // @TODO: Check if component manager for T already exists
+        self.storage.register::<T>();
+        self.last_updated_map.insert(TypeId::of::<T>(), self.frame);
+        self
+    }
+
+    pub(crate) fn register_tag<T: 'static + Tag>(&mut self) -> &mut Self {
+        const { assert!(size_of::<T>() == 0, "tags can not carry data") };
+        self.storage.register_tag::<T>();
+        self.last_updated_map.insert(TypeId::of::<T>(), self.frame);
+        self
+    }
+
+    pub(crate) fn borrow_components<T: 'static + Component>(
         &self,
-        entity_id: usize,
-    ) -> Option<(&mut T1, &mut T2)> {
-        let type_id1 = TypeId::of::<T1>();
-        let type_id2 = TypeId::of::<T2>();
+    ) -> Result<ComponentsRef<'_, T>, EcsError> {
+        self.storage.borrow::<T>()
+    }
 
-        let manager1: &mut ComponentManager<T1> =
-            cast_manager_mut_unsafe(self.manager_map.get(&type_id1).unwrap());
-        let manager2: &mut ComponentManager<T2> =
-            cast_manager_mut_unsafe(self.manager_map.get(&type_id2).unwrap());
+    pub(crate) fn borrow_components_mut<T: 'static + Component>(
+        &self,
+    ) -> Result<ComponentsRefMut<'_, T>, EcsError> {
+        self.storage.borrow_mut::<T>()
+    }
 
-        let t1 = manager1.borrow_components_mut(entity_id).unwrap();
-        let t2 = manager2.borrow_components_mut(entity_id).unwrap();
+    /// Fails if `T` is not registered or is borrowed mutably, works for tags as well
+    pub(crate) fn check_readable<T: 'static + Component>(&self) -> Result<(), EcsError> {
+        self.storage.check_readable::<T>()
+    }
 
-        Some((t1, t2))
+    pub(crate) fn has_component_of(
+        &self,
+        entity: Entity,
+        type_id: &TypeId,
+    ) -> Result<bool, EcsError> {
+        self.storage.has(entity, type_id)
     }
 
-    pub(crate) fn borrow_component_triple_mut<
-        T1: 'static + Component,
-        T2: 'static + Component,
-        T3: 'static + Component,
-    >(
+    pub(crate) fn component_ticks(
         &self,
-        entity_id: usize,
-    ) -> Option<(&mut T1, &mut T2, &mut T3)> {
-        let type_id1 = TypeId::of::<T1>();
-        let type_id2 = TypeId::of::<T2>();
-        let type_id3 = TypeId::of::<T3>();
+        entity: Entity,
+        type_id: &TypeId,
+    ) -> Result<Option<ComponentTicks>, EcsError> {
+        self.storage.ticks(entity, type_id)
+    }
 
-        let manager1: &mut ComponentManager<T1> =
-            cast_manager_mut_unsafe(self.manager_map.get(&type_id1).unwrap());
-        let manager2: &mut ComponentManager<T2> =
-            cast_manager_mut_unsafe(self.manager_map.get(&type_id2).unwrap());
-        let manager3: &mut ComponentManager<T3> =
-            cast_manager_mut_unsafe(self.manager_map.get(&type_id3).unwrap());
+    /// Entities that may have every type in `type_ids`, filter them with `has_component_of`
+    pub(crate) fn candidates(
+        &self,
+        type_ids: &[TypeId],
+        dst: &mut Vec<Entity>,
+    ) -> Result<(), EcsError> {
+        self.storage.candidates(type_ids, dst)
+    }
 
-        let t1 = manager1.borrow_components_mut(entity_id).unwrap();
-        let t2 = manager2.borrow_components_mut(entity_id).unwrap();
-        let t3 = manager3.borrow_components_mut(entity_id).unwrap();
+    pub(crate) fn get_update_frame_of(&self, type_id: &TypeId) -> u64 {
+        self.last_updated_map
+            .get(type_id)
+            .copied()
+            .unwrap_or_default()
+    }
 
-        Some((t1, t2, t3))
+    pub(crate) fn get_frame(&self) -> u64 {
+        self.frame
     }
 
     pub(crate) fn step_frame(&mut self) {
         self.frame += 1;
     }
 
-    pub(crate) fn borrow_component<T: Component + 'static>(&self, entity_id: usize) -> Option<&T> {
-        match self.has_component_manager::<T>() {
-            true => self
-                .borrow_component_manager::<T>()
-                .borrow_component(entity_id),
-            false => None,
-        }
+    /// Number of `Simulation::update` calls so far, unlike frames it does not depend on the
+    /// number of systems, so it is what compaction counts idle slots in
+    pub(crate) fn get_update(&self) -> u64 {
+        self.updates
     }
 
-    fn has_component_manager<T: 'static>(&self) -> bool {
-        let type_id = TypeId::of::<T>();
-        self.manager_map.contains_key(&type_id)
+    pub(crate) fn step_update(&mut self) {
+        self.updates += 1;
     }
 
-    pub(crate) fn borrow_components<T: Component + 'static>(&self) -> Option<&Vec<T>> {
-        match self.has_component_manager::<T>() {
-            true => self.borrow_component_manager::<T>().borrow_components(),
-            false => None,
-        }
+    /// Tick that changes made during the current frame are stamped with
+    ///
+    /// Changes are stamped with the next frame while cached id lists are stamped with the frame
+    /// they were built in, so a list built earlier in this same frame is still seen as outdated.
+    pub(crate) fn change_tick(&self) -> u64 {
+        self.frame + 1
     }
 
-    pub(crate) fn borrow_component_mut<T: Component + 'static>(
-        &mut self,
-        entity_id: usize,
-    ) -> Option<&mut T> {
-        match self.has_component_manager::<T>() {
-            true => self
-                .borrow_component_manager_mut::<T>()
-                .borrow_component_mut(entity_id),
-            false => None,
-        }
+    /// Commands queued by hooks since the last time they were taken
+    pub(crate) fn take_hook_commands(&mut self) -> Commands {
+        mem::take(self.hook_commands.get_mut().unwrap())
+    }
+
+    /// Forgets the removed components that were logged before the tick `before`
+    pub(crate) fn clear_removed(&mut self, before: u64) {
+        self.storage.clear_removed(before);
     }
 }
 
-#[allow(clippy::mut_from_ref, clippy::borrowed_box)]
-fn cast_manager_mut_unsafe<T: 'static + Component>(
-    boxed_component: &Box<dyn ComponentManagerTrait>,
-) -> &mut ComponentManager<T> {
-    // Deref box ref to box, and then deref box internal value,
-    // then taking a reference to the value in the box
-    let dyn_manager = &**boxed_component;
-
-    // cast the dyn ComponentManagerTrait to an actual ComponentManager<T>
-    // based on T value
-    let manager: &ComponentManager<T> = cast_manager(dyn_manager);
-
-    // Casting our ComponentManager<T> into a raw mutable pointer
-    let ptr = manager as *const ComponentManager<T> as *mut ComponentManager<T>;
-
-    // unsafely transmute our raw mutable pointer
-    // to a mutable ref of a ComponentManager<T>
-    let manager: &mut ComponentManager<T> = unsafe { transmute(&mut *ptr) };
-    manager
+fn remap_components<T: 'static + Component + RemapEntities>(em: &EntityManager, remap: &Remap) {
+    if let Ok(mut components) = em.borrow_components_mut::<T>() {
+        for (_, component, _) in components.iter_mut() {
+            component.remap_entities(remap);
+        }
+    }
 }
 
-fn cast_manager<T: 'static + Component>(
-    unknown: &dyn ComponentManagerTrait,
-) -> &ComponentManager<T> {
-    unknown
-        .as_any()
-        .downcast_ref::<ComponentManager<T>>()
-        .unwrap()
+fn remap_resource<T: 'static + Send + Sync + RemapEntities>(em: &EntityManager, remap: &Remap) {
+    if let Ok(mut resource) = em.resource_mut::<T>() {
+        resource.remap_entities(remap);
+    }
 }
 
-fn cast_manager_mut<T: 'static + Component>(
-    unknown: &mut dyn ComponentManagerTrait,
-) -> &mut ComponentManager<T> {
-    unknown
-        .as_any_mut()
-        .downcast_mut::<ComponentManager<T>>()
-        .unwrap()
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+    use crate::core::ecs::{
+        entityidaccessor::EntityIdAccessor, event::EventReader, filter::Changed,
+    };
+
+    struct Position;
+    struct Velocity;
+
+    impl Component for Position {}
+    impl Component for Velocity {}
+
+    fn setup(mode: StorageMode) -> (EntityManager, Entity) {
+        let mut em = EntityManager::with_storage(mode);
+        em.register::<Position>();
+        em.register::<Velocity>();
+        let entity = em.spawn((Position, Velocity)).unwrap();
+        (em, entity)
+    }
+
+    #[test]
+    pub fn should_remove_single_component() {
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (mut em, entity) = setup(mode);
+            let mut eia = EntityIdAccessor::new();
+            assert_eq!(eia.borrow_ids::<Velocity>(&em).unwrap(), &vec![entity]);
+
+            em.step_frame();
+            em.remove_component::<Velocity>(entity).unwrap();
+            em.step_frame();
+
+            assert!(em.has_entity(entity));
+            assert!(em.has_component::<Position>(entity).unwrap());
+            assert!(!em.has_component::<Velocity>(entity).unwrap());
+            assert_eq!(
+                em.component_types(entity),
+                Ok(vec![TypeId::of::<Position>()])
+            );
+            assert!(eia.borrow_ids::<Velocity>(&em).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    pub fn should_not_report_components_of_removed_entity() {
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (mut em, entity) = setup(mode);
+            em.remove_entity(entity).unwrap();
+
+            assert!(!em.has_component::<Position>(entity).unwrap());
+            assert_eq!(
+                em.component_types(entity),
+                Err(EcsError::StaleEntity(entity))
+            );
+        }
+    }
+
+    #[test]
+    pub fn should_keep_components_of_compacted_entities() {
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (mut em, first) = setup(mode);
+            let second = em.spawn((Position,)).unwrap();
+            let mut eia = EntityIdAccessor::new();
+            assert_eq!(eia.borrow_ids::<Position>(&em).unwrap().len(), 2);
+
+            em.remove_entity(first).unwrap();
+            let remap = em.compact(Compaction { min_idle: 0 });
+            let moved = remap.get(second).unwrap();
+
+            assert_eq!(moved.index(), 0);
+            assert_eq!(em.entities.slot_count(), 1);
+            assert!(!em.has_entity(second));
+            assert!(em.has_component::<Position>(moved).unwrap());
+            assert!(!em.has_component::<Velocity>(moved).unwrap());
+            assert_eq!(eia.borrow_ids::<Position>(&em).unwrap(), &vec![moved]);
+        }
+    }
+
+    #[test]
+    pub fn should_remap_registered_handles_when_compacting() {
+        struct Target(Entity);
+        impl Component for Target {}
+        impl RemapEntities for Target {
+            fn remap_entities(&mut self, remap: &Remap) {
+                self.0.remap_entities(remap);
+            }
+        }
+
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (mut em, first) = setup(mode);
+            em.register::<Target>();
+            em.insert_resource(Some(first));
+            em.insert_resource(Events::<Entity>::default());
+            em.remap_component::<Target>()
+                .remap_resource::<Option<Entity>>()
+                .remap_resource::<Events<Entity>>();
+            let hunter = em.spawn((Position,)).unwrap();
+            let second = em.spawn((Position,)).unwrap();
+            em.add_component_to_entity(hunter, Target(second)).unwrap();
+            *em.resource_mut::<Option<Entity>>().unwrap() = Some(second);
+            em.event_writer::<Entity>().unwrap().send(second);
+
+            em.remove_entity(first).unwrap();
+            let remap = em.compact(Compaction { min_idle: 0 });
+            let second = remap.get(second).unwrap();
+            let mut reader = EventReader::new();
+
+            let targets = em.borrow_components::<Target>().unwrap();
+            assert_eq!(targets.get(hunter).unwrap().0, second);
+            assert_eq!(*em.resource::<Option<Entity>>().unwrap(), Some(second));
+            let events = em.events::<Entity>().unwrap();
+            assert_eq!(reader.read(&events).collect::<Vec<_>>(), [&second]);
+        }
+    }
+
+    #[test]
+    pub fn should_report_errors_instead_of_panicking() {
+        struct Unregistered;
+        impl Component for Unregistered {}
+        let (mut em, entity) = setup(StorageMode::SparseSet);
+        let unregistered = EcsError::UnregisteredComponent(type_name::<Unregistered>());
+
+        assert_eq!(
+            em.add_component_to_entity(entity, Unregistered),
+            Err(unregistered.clone())
+        );
+        assert_eq!(
+            em.remove_component::<Unregistered>(entity),
+            Err(unregistered)
+        );
+        assert!(!em.has_component::<Unregistered>(entity).unwrap());
+
+        em.remove_entity(entity).unwrap();
+        assert_eq!(em.remove_entity(entity), Err(EcsError::StaleEntity(entity)));
+        assert_eq!(
+            em.add_component_to_entity(entity, Position),
+            Err(EcsError::StaleEntity(entity))
+        );
+    }
+
+    #[test]
+    pub fn should_fail_on_aliasing_borrow_instead_of_panicking() {
+        struct Score;
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (mut em, entity) = setup(mode);
+            em.insert_resource(Score);
+            let aliasing = EcsError::AliasingBorrow(type_name::<Position>());
+            let position = TypeId::of::<Position>();
+            let mut eia = EntityIdAccessor::new();
+
+            let positions = em.borrow_components_mut::<Position>().unwrap();
+            assert_eq!(em.component_ticks(entity, &position), Err(aliasing.clone()));
+            assert_eq!(
+                eia.borrow_ids_filtered::<Velocity, Changed<Position>>(&em)
+                    .err(),
+                Some(aliasing.clone())
+            );
+            // Archetype storage finds entities and their types without reading any column
+            if mode == StorageMode::SparseSet {
+                assert_eq!(em.has_component::<Position>(entity), Err(aliasing.clone()));
+                assert_eq!(em.component_types(entity), Err(aliasing.clone()));
+                assert_eq!(em.candidates(&[position], &mut vec![]), Err(aliasing));
+            }
+            drop(positions);
+            assert!(em.has_component::<Position>(entity).unwrap());
+
+            let score = em.resource_mut::<Score>().unwrap();
+            assert_eq!(
+                em.resource::<Score>().err(),
+                Some(EcsError::AliasingBorrow(type_name::<Score>()))
+            );
+            drop(score);
+            assert!(em.resource::<Score>().is_ok());
+        }
+    }
+
+    #[test]
+    pub fn should_tell_poisoned_borrow_from_aliasing_borrow() {
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (em, entity) = setup(mode);
+            let poisoned = EcsError::PoisonedBorrow(type_name::<Position>());
+
+            let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _positions = em.borrow_components_mut::<Position>().unwrap();
+                panic!("system panicked");
+            }));
+            assert!(panicked.is_err());
+            assert_eq!(em.query::<&Position>().err(), Some(poisoned.clone()));
+            assert_eq!(
+                em.component_ticks(entity, &TypeId::of::<Position>()),
+                Err(poisoned)
+            );
+        }
+    }
 }
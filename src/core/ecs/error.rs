@@ -0,0 +1,57 @@
+use std::{error::Error, fmt, sync::TryLockError};
+
+use super::entities::Entity;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EcsError {
+    /// The component type, named by the field, was never registered
+    UnregisteredComponent(&'static str),
+    /// The entity handle was never handed out by this `EntityManager`
+    NoSuchEntity(Entity),
+    /// The entity has been removed, its slot may already belong to another entity
+    StaleEntity(Entity),
+    /// The component or resource, named by the field, is already borrowed in a conflicting way
+    AliasingBorrow(&'static str),
+    /// A system panicked while it borrowed the component or resource named by the field
+    PoisonedBorrow(&'static str),
+    /// The tag, named by the field, was fetched like a component, tags only work in filters
+    FetchedTag(&'static str),
+    /// The resource type, named by the field, was never inserted
+    MissingResource(&'static str),
+}
+
+impl fmt::Display for EcsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EcsError::UnregisteredComponent(name) => {
+                write!(f, "component {} is not registered", name)
+            }
+            EcsError::NoSuchEntity(entity) => write!(f, "entity {:?} does not exist", entity),
+            EcsError::StaleEntity(entity) => write!(f, "entity {:?} has been removed", entity),
+            EcsError::AliasingBorrow(name) => write!(f, "{} is already borrowed", name),
+            EcsError::PoisonedBorrow(name) => {
+                write!(f, "{} was borrowed by a system that panicked", name)
+            }
+            EcsError::FetchedTag(name) => {
+                write!(
+                    f,
+                    "tag {} has no values to fetch, filter on it instead",
+                    name
+                )
+            }
+            EcsError::MissingResource(name) => write!(f, "resource {} is missing", name),
+        }
+    }
+}
+
+impl Error for EcsError {}
+
+impl EcsError {
+    /// The error for a failed `try_read` or `try_write` of the component or resource `name`
+    pub(crate) fn from_lock<G>(error: TryLockError<G>, name: &'static str) -> Self {
+        match error {
+            TryLockError::WouldBlock => EcsError::AliasingBorrow(name),
+            TryLockError::Poisoned(_) => EcsError::PoisonedBorrow(name),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::marker::PhantomData;
+
+use super::{
+    entities::{Remap, RemapEntities},
+    resource::ResMut,
+};
+
+struct EventInstance<T> {
+    id: usize,
+    event: T,
+}
+
+/// Double buffered queue of events of type `T`
+///
+/// Events are kept for the tick they are sent in and the tick after that, so a reader running
+/// before the writer still gets to see them. `Simulation` swaps the buffers at the start of every
+/// tick for each event type registered with `add_event`.
+pub struct Events<T> {
+    previous: Vec<EventInstance<T>>,
+    current: Vec<EventInstance<T>>,
+    event_count: usize,
+}
+
+impl<T> Default for Events<T> {
+    fn default() -> Self {
+        Self {
+            previous: vec![],
+            current: vec![],
+            event_count: 0,
+        }
+    }
+}
+
+impl<T> Events<T> {
+    pub fn send(&mut self, event: T) {
+        self.current.push(EventInstance {
+            id: self.event_count,
+            event,
+        });
+        self.event_count += 1;
+    }
+
+    /// Drops the events of the previous tick and starts a new buffer for this tick
+    pub fn update(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.previous.is_empty() && self.current.is_empty()
+    }
+
+    fn iter_from(&self, id: usize) -> impl Iterator<Item = &T> + '_ {
+        self.previous
+            .iter()
+            .chain(self.current.iter())
+            .filter(move |instance| instance.id >= id)
+            .map(|instance| &instance.event)
+    }
+}
+
+/// Pending events hold on to their handles until they are dropped, so they follow compactions too
+impl<T: RemapEntities> RemapEntities for Events<T> {
+    fn remap_entities(&mut self, remap: &Remap) {
+        for instance in self.previous.iter_mut().chain(self.current.iter_mut()) {
+            instance.event.remap_entities(remap);
+        }
+    }
+}
+
+/// Sends events of type `T`, fetched with `EntityManager::event_writer`
+pub struct EventWriter<'a, T> {
+    events: ResMut<'a, Events<T>>,
+}
+
+impl<'a, T: 'static> EventWriter<'a, T> {
+    pub(crate) fn new(events: ResMut<'a, Events<T>>) -> Self {
+        Self { events }
+    }
+
+    pub fn send(&mut self, event: T) {
+        self.events.send(event);
+    }
+}
+
+/// Reads events of type `T`, owned by the system reading them
+///
+/// The reader remembers how far it has read, so every event is handed out once per reader as
+/// long as the reader runs at least once every other tick.
+pub struct EventReader<T> {
+    last_event_count: usize,
+    marker: PhantomData<T>,
+}
+
+impl<T> Default for EventReader<T> {
+    fn default() -> Self {
+        Self {
+            last_event_count: 0,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> EventReader<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read<'a>(&mut self, events: &'a Events<T>) -> impl Iterator<Item = &'a T> + 'a {
+        let from = self.last_event_count;
+        self.last_event_count = events.event_count;
+        events.iter_from(from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct AppleEaten(u8);
+
+    #[test]
+    pub fn should_read_each_event_once() {
+        let mut events = Events::default();
+        let mut reader = EventReader::new();
+        events.send(AppleEaten(1));
+
+        assert_eq!(reader.read(&events).map(|e| e.0).collect::<Vec<_>>(), [1]);
+        assert_eq!(reader.read(&events).count(), 0);
+    }
+
+    #[test]
+    pub fn should_keep_events_for_one_extra_tick() {
+        let mut events = Events::default();
+        let mut reader = EventReader::new();
+        events.send(AppleEaten(1));
+        events.update();
+        events.send(AppleEaten(2));
+
+        assert_eq!(
+            reader.read(&events).map(|e| e.0).collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    pub fn should_drop_events_after_two_ticks() {
+        let mut events = Events::default();
+        let mut reader = EventReader::new();
+        events.send(AppleEaten(1));
+        events.update();
+        events.update();
+
+        assert!(events.is_empty());
+        assert_eq!(reader.read(&events).count(), 0);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{any::TypeId, marker::PhantomData};
+
+use super::{entities::Entity, entitymanager::EntityManager, error::EcsError, Component};
+
+/// Components an entity must all have, implemented for a single component and tuples of them
+pub trait ComponentSet: 'static {
+    fn type_ids(ids: &mut Vec<TypeId>);
+    /// Fails if a component in the set is borrowed mutably right now
+    fn matches(em: &EntityManager, entity: Entity) -> Result<bool, EcsError>;
+
+    /// Fails if a component in the set is not registered or is borrowed mutably right now
+    fn check(em: &EntityManager) -> Result<(), EcsError>;
+}
+
+impl<T: 'static + Component> ComponentSet for T {
+    fn type_ids(ids: &mut Vec<TypeId>) {
+        ids.push(TypeId::of::<T>());
+    }
+
+    fn matches(em: &EntityManager, entity: Entity) -> Result<bool, EcsError> {
+        em.has_component_of(entity, &TypeId::of::<T>())
+    }
+
+    fn check(em: &EntityManager) -> Result<(), EcsError> {
+        em.check_readable::<T>()
+    }
+}
+
+macro_rules! impl_component_set {
+    ($first:ident $(, $name:ident)*) => {
+        impl<$first: ComponentSet $(, $name: ComponentSet)*> ComponentSet for ($first, $($name,)*) {
+            fn type_ids(ids: &mut Vec<TypeId>) {
+                $first::type_ids(ids);
+                $($name::type_ids(ids);)*
+            }
+
+            fn matches(em: &EntityManager, entity: Entity) -> Result<bool, EcsError> {
+                if !$first::matches(em, entity)? {
+                    return Ok(false);
+                }
+                $(if !$name::matches(em, entity)? {
+                    return Ok(false);
+                })*
+                Ok(true)
+            }
+
+            fn check(em: &EntityManager) -> Result<(), EcsError> {
+                $first::check(em)?;
+                $($name::check(em)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_component_set!(A);
+impl_component_set!(A, B);
+impl_component_set!(A, B, C);
+impl_component_set!(A, B, C, D);
+impl_component_set!(A, B, C, D, E);
+impl_component_set!(A, B, C, D, E, F);
+
+/// Extra condition on the entities of a `ComponentSet`, see `With`, `Without`, `Added` and
+/// `Changed`
+///
+/// `since` is the tick the system asking last ran at, filters on changes keep entities whose
+/// component changed after it.
+pub trait Filter: 'static {
+    fn type_ids(ids: &mut Vec<TypeId>);
+    fn matches(em: &EntityManager, entity: Entity, since: u64) -> Result<bool, EcsError>;
+
+    /// Fails if a component the filter looks at is not registered or is borrowed mutably
+    fn check(em: &EntityManager) -> Result<(), EcsError>;
+
+    /// Whether the filter looks at change ticks, which move without any structural change
+    fn tracks_changes() -> bool {
+        false
+    }
+}
+
+/// Only keep entities that have a `T`, without caring about its value
+pub struct With<T>(PhantomData<T>);
+
+/// Only keep entities that do not have a `T`
+pub struct Without<T>(PhantomData<T>);
+
+/// Only keep entities whose `T` was added since the system last ran
+pub struct Added<T>(PhantomData<T>);
+
+/// Only keep entities whose `T` was added or written to since the system last ran
+pub struct Changed<T>(PhantomData<T>);
+
+impl<T: 'static + Component> Filter for With<T> {
+    fn type_ids(ids: &mut Vec<TypeId>) {
+        ids.push(TypeId::of::<T>());
+    }
+
+    fn matches(em: &EntityManager, entity: Entity, _since: u64) -> Result<bool, EcsError> {
+        em.has_component_of(entity, &TypeId::of::<T>())
+    }
+
+    fn check(em: &EntityManager) -> Result<(), EcsError> {
+        T::check(em)
+    }
+}
+
+impl<T: 'static + Component> Filter for Without<T> {
+    fn type_ids(ids: &mut Vec<TypeId>) {
+        ids.push(TypeId::of::<T>());
+    }
+
+    fn matches(em: &EntityManager, entity: Entity, _since: u64) -> Result<bool, EcsError> {
+        Ok(!em.has_component_of(entity, &TypeId::of::<T>())?)
+    }
+
+    fn check(em: &EntityManager) -> Result<(), EcsError> {
+        T::check(em)
+    }
+}
+
+impl<T: 'static + Component> Filter for Added<T> {
+    fn type_ids(ids: &mut Vec<TypeId>) {
+        ids.push(TypeId::of::<T>());
+    }
+
+    fn matches(em: &EntityManager, entity: Entity, since: u64) -> Result<bool, EcsError> {
+        Ok(em
+            .component_ticks(entity, &TypeId::of::<T>())?
+            .is_some_and(|ticks| ticks.is_added(since)))
+    }
+
+    fn check(em: &EntityManager) -> Result<(), EcsError> {
+        T::check(em)
+    }
+
+    fn tracks_changes() -> bool {
+        true
+    }
+}
+
+impl<T: 'static + Component> Filter for Changed<T> {
+    fn type_ids(ids: &mut Vec<TypeId>) {
+        ids.push(TypeId::of::<T>());
+    }
+
+    fn matches(em: &EntityManager, entity: Entity, since: u64) -> Result<bool, EcsError> {
+        Ok(em
+            .component_ticks(entity, &TypeId::of::<T>())?
+            .is_some_and(|ticks| ticks.is_changed(since)))
+    }
+
+    fn check(em: &EntityManager) -> Result<(), EcsError> {
+        T::check(em)
+    }
+
+    fn tracks_changes() -> bool {
+        true
+    }
+}
+
+impl Filter for () {
+    fn type_ids(_ids: &mut Vec<TypeId>) {}
+
+    fn matches(_em: &EntityManager, _entity: Entity, _since: u64) -> Result<bool, EcsError> {
+        Ok(true)
+    }
+
+    fn check(_em: &EntityManager) -> Result<(), EcsError> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_filter {
+    ($($name:ident),+) => {
+        impl<$($name: Filter),+> Filter for ($($name,)+) {
+            fn type_ids(ids: &mut Vec<TypeId>) {
+                $($name::type_ids(ids);)+
+            }
+
+            fn matches(em: &EntityManager, entity: Entity, since: u64) -> Result<bool, EcsError> {
+                $(if !$name::matches(em, entity, since)? {
+                    return Ok(false);
+                })+
+                Ok(true)
+            }
+
+            fn check(em: &EntityManager) -> Result<(), EcsError> {
+                $($name::check(em)?;)+
+                Ok(())
+            }
+
+            fn tracks_changes() -> bool {
+                $($name::tracks_changes())||+
+            }
+        }
+    };
+}
+
+impl_filter!(A);
+impl_filter!(A, B);
+impl_filter!(A, B, C);
+impl_filter!(A, B, C, D);
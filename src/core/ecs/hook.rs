@@ -0,0 +1,156 @@
+use std::any::TypeId;
+
+use super::{command::Commands, entities::Entity, entitymanager::EntityManager, error::EcsError};
+
+/// Callback run when a component is added to or removed from an entity
+///
+/// Hooks only get a shared `EntityManager` since they run in the middle of a structural change,
+/// anything structural they want to do goes through `Commands`. Those are applied at the next
+/// point the `Schedule` applies the commands of systems.
+pub type HookFn = Box<dyn Fn(&EntityManager, Entity, &mut Commands) + Send + Sync>;
+
+struct Hook {
+    type_id: TypeId,
+    callback: HookFn,
+}
+
+/// Hooks registered with `EntityManager::on_add` and `EntityManager::on_remove`
+///
+/// Hooks run in the order they were registered. Despawning an entity runs the remove hooks of
+/// all of its components, in that same order and before any component is gone.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    on_add: Vec<Hook>,
+    on_remove: Vec<Hook>,
+}
+
+impl Hooks {
+    pub fn add_on_add(&mut self, type_id: TypeId, callback: HookFn) {
+        self.on_add.push(Hook { type_id, callback });
+    }
+
+    pub fn add_on_remove(&mut self, type_id: TypeId, callback: HookFn) {
+        self.on_remove.push(Hook { type_id, callback });
+    }
+
+    pub fn run_on_add(
+        &self,
+        em: &EntityManager,
+        entity: Entity,
+        type_id: &TypeId,
+        commands: &mut Commands,
+    ) {
+        run(&self.on_add, em, entity, commands, |id| id == type_id);
+    }
+
+    pub fn run_on_remove(
+        &self,
+        em: &EntityManager,
+        entity: Entity,
+        type_id: &TypeId,
+        commands: &mut Commands,
+    ) {
+        run(&self.on_remove, em, entity, commands, |id| id == type_id);
+    }
+
+    /// Runs the remove hooks of every component the entity has
+    pub fn run_on_despawn(
+        &self,
+        em: &EntityManager,
+        entity: Entity,
+        commands: &mut Commands,
+    ) -> Result<(), EcsError> {
+        let type_ids = em.component_types(entity)?;
+        run(&self.on_remove, em, entity, commands, |id| {
+            type_ids.contains(id)
+        });
+        Ok(())
+    }
+}
+
+fn run(
+    hooks: &[Hook],
+    em: &EntityManager,
+    entity: Entity,
+    commands: &mut Commands,
+    applies: impl Fn(&TypeId) -> bool,
+) {
+    for hook in hooks.iter().filter(|hook| applies(&hook.type_id)) {
+        (hook.callback)(em, entity, commands);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::core::ecs::{schedule::Schedule, Component};
+
+    use super::*;
+
+    struct Position;
+    struct Apple;
+    struct Particle;
+
+    impl Component for Position {}
+    impl Component for Apple {}
+    impl Component for Particle {}
+
+    fn setup() -> (EntityManager, Arc<Mutex<Vec<&'static str>>>) {
+        let mut em = EntityManager::new();
+        em.register::<Position>();
+        em.register::<Apple>();
+        em.register::<Particle>();
+        (em, Arc::new(Mutex::new(vec![])))
+    }
+
+    fn record(
+        log: &Arc<Mutex<Vec<&'static str>>>,
+        name: &'static str,
+    ) -> impl Fn(&EntityManager, Entity, &mut Commands) + Send + Sync + 'static {
+        let log = log.clone();
+        move |_, _, _| log.lock().unwrap().push(name)
+    }
+
+    #[test]
+    pub fn should_run_hooks_in_registration_order() {
+        let (mut em, log) = setup();
+        em.on_remove::<Position>(record(&log, "remove position"));
+        em.on_add::<Apple>(record(&log, "add apple"));
+        em.on_remove::<Apple>(record(&log, "remove apple"));
+        em.on_add::<Position>(record(&log, "add position"));
+
+        let entity = em.spawn((Apple, Position)).unwrap();
+        em.add_component_to_entity(entity, Apple).unwrap();
+        em.remove_component::<Particle>(entity).unwrap();
+        em.remove_entity(entity).unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "add apple",
+                "add position",
+                "remove position",
+                "remove apple"
+            ]
+        );
+    }
+
+    #[test]
+    pub fn should_apply_hook_commands_in_schedule() {
+        let (mut em, _) = setup();
+        em.on_remove::<Apple>(|em, entity, commands| {
+            if em.has_component::<Apple>(entity).unwrap() {
+                commands.spawn().insert(Particle);
+            }
+        });
+        let apple = em.spawn((Apple,)).unwrap();
+        em.remove_component::<Apple>(apple).unwrap();
+
+        let mut schedule = Schedule::new();
+        assert!(schedule.run(&mut em).is_empty());
+
+        let particles = em.borrow_components::<Particle>().unwrap().entity_ids();
+        assert_eq!(particles.len(), 1);
+    }
+}
@@ -1,19 +1,64 @@
 use core::any::Any;
-use std::{any::TypeId, collections::HashMap};
+use std::{
+    any::{type_name, TypeId},
+    marker::PhantomData,
+};
+
+use self::entities::{Entity, Remap};
+
+pub mod archetype;
+pub mod bundle;
+pub mod command;
 pub mod entities;
 pub mod entityidaccessor;
 pub mod entitymanager;
+pub mod error;
+pub mod event;
+pub mod filter;
+pub mod hook;
+pub mod plugin;
+pub mod query;
+pub mod removed;
+pub mod resource;
+pub mod schedule;
 pub mod simulation;
+pub mod storage;
 pub mod system;
 
-pub trait Component {}
+/// Data attached to an entity, it has to be shareable between threads since systems may run in
+/// parallel
+pub trait Component: Send + Sync {}
+
+/// Zero-sized marker component such as `Collidable`, it only tells something about an entity by
+/// being there
+///
+/// Tags are registered with `register_tag`, which refuses types that carry data, and are stored in
+/// a `TagManager` that only keeps the set of entities having the tag. They are meant to be used in
+/// `With` and `Without` filters, fetching a tag in a query fails with `EcsError::FetchedTag`.
+pub trait Tag: Component + Default {}
 
-pub trait ComponentManagerTrait {
+pub trait ComponentManagerTrait: Send + Sync {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
-    fn has(&self, entity_id: usize) -> bool;
-    fn remove(&mut self, entity_id: usize);
+    fn has(&self, entity: Entity) -> bool;
+    fn ticks(&self, entity: Entity) -> Option<ComponentTicks>;
+    fn entity_ids(&self) -> &[Entity];
+    fn remove(&mut self, entity: Entity, tick: u64);
+    fn clear_removed(&mut self, before: u64);
     fn get_type_id(&self) -> TypeId;
+    fn type_name(&self) -> &'static str;
+
+    /// An empty manager for the same component type
+    fn new_empty(&self) -> Box<dyn ComponentManagerTrait>;
+
+    // Row based access used by archetype tables, which keep the rows of all their columns in
+    // step and look entities up through their own locations instead of the sparse index.
+    fn row_ticks(&self, row: usize) -> Option<ComponentTicks>;
+    fn move_row(&mut self, row: usize, to: &mut dyn ComponentManagerTrait);
+    fn remove_row(&mut self, row: usize, tick: u64);
+
+    /// Swaps the handles of moved entities and shrinks the storage to entity indices below `len`
+    fn remap(&mut self, remap: &Remap, len: usize);
 }
 
 impl<T: 'static + Component> ComponentManagerTrait for ComponentManager<T> {
@@ -25,19 +70,62 @@ impl<T: 'static + Component> ComponentManagerTrait for ComponentManager<T> {
         self as &dyn Any
     }
 
-    fn has(&self, entity_id: usize) -> bool {
+    fn has(&self, entity: Entity) -> bool {
         let manager = cast_manager::<T>(self);
-        manager.has(entity_id)
+        manager.has(entity)
     }
 
-    fn remove(&mut self, entity_id: usize) {
+    fn ticks(&self, entity: Entity) -> Option<ComponentTicks> {
+        let manager = cast_manager::<T>(self);
+        manager.ticks(entity)
+    }
+
+    fn entity_ids(&self) -> &[Entity] {
+        &self.entity_ids
+    }
+
+    fn remove(&mut self, entity: Entity, tick: u64) {
+        let manager = cast_manager_mut::<T>(self);
+        manager.remove(entity, tick);
+    }
+
+    fn clear_removed(&mut self, before: u64) {
         let manager = cast_manager_mut::<T>(self);
-        manager.remove(entity_id);
+        manager.removed.retain(|(_, tick)| *tick >= before);
     }
 
     fn get_type_id(&self) -> TypeId {
         TypeId::of::<T>()
     }
+
+    fn type_name(&self) -> &'static str {
+        type_name::<T>()
+    }
+
+    fn new_empty(&self) -> Box<dyn ComponentManagerTrait> {
+        Box::new(ComponentManager::<T>::new())
+    }
+
+    fn row_ticks(&self, row: usize) -> Option<ComponentTicks> {
+        Some(self.ticks[row])
+    }
+
+    fn move_row(&mut self, row: usize, to: &mut dyn ComponentManagerTrait) {
+        let entity = self.entity_ids[row];
+        let (component, ticks) = self.swap_remove_row(row);
+        cast_manager_mut::<T>(to).push_row(entity, component, ticks);
+    }
+
+    fn remove_row(&mut self, row: usize, tick: u64) {
+        let entity = self.entity_ids[row];
+        self.swap_remove_row(row);
+        self.removed.push((entity, tick));
+    }
+
+    fn remap(&mut self, remap: &Remap, len: usize) {
+        let manager = cast_manager_mut::<T>(self);
+        manager.remap(remap, len);
+    }
 }
 
 pub fn cast_manager<T: 'static + Component>(
@@ -58,11 +146,45 @@ pub fn cast_manager_mut<T: 'static + Component>(
         .unwrap()
 }
 
+/// Ticks at which a component was added and last changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentTicks {
+    pub added: u64,
+    pub changed: u64,
+}
+
+impl ComponentTicks {
+    fn new(tick: u64) -> Self {
+        Self {
+            added: tick,
+            changed: tick,
+        }
+    }
+
+    pub fn is_added(&self, since: u64) -> bool {
+        self.added > since
+    }
+
+    pub fn is_changed(&self, since: u64) -> bool {
+        self.changed > since
+    }
+}
+
+/// Dense storage for all components of type `T`
+///
+/// Components are packed in `components` with the owning entity and the change ticks at the
+/// same index in `entity_ids` and `ticks`. `sparse` is indexed by entity index and holds the
+/// position in the dense vectors, so lookups never hash and iteration stays on the packed
+/// vectors. Since `sparse` is indexed on the entity index only, every lookup also compares the
+/// stored handle so a stale handle never resolves to the component of the entity that reused its
+/// slot. Removed components are logged in `removed` until they are cleared.
 #[derive(Default)]
 pub struct ComponentManager<T> {
     components: Vec<T>,
-    entity_ids: Vec<usize>,
-    entity_ids_map: HashMap<usize, usize>,
+    entity_ids: Vec<Entity>,
+    ticks: Vec<ComponentTicks>,
+    sparse: Vec<Option<usize>>,
+    removed: Vec<(Entity, u64)>,
 }
 
 impl<T: Component> ComponentManager<T> {
@@ -70,60 +192,353 @@ impl<T: Component> ComponentManager<T> {
         Self {
             components: vec![],
             entity_ids: vec![],
-            entity_ids_map: HashMap::new(),
+            ticks: vec![],
+            sparse: vec![],
+            removed: vec![],
         }
     }
 
-    pub fn add(&mut self, entity_id: usize, component: T) {
+    /// Adds the component at `tick`, a component that is already present counts as changed
+    pub fn add(&mut self, entity: Entity, component: T, tick: u64) {
+        if let Some(index) = self.index_of(entity) {
+            self.components[index] = component;
+            self.ticks[index].changed = tick;
+            return;
+        }
         self.components.push(component);
-        self.entity_ids.push(entity_id);
-        let index = self.entity_ids.len() - 1;
-        self.entity_ids_map.insert(entity_id, index);
+        self.entity_ids.push(entity);
+        self.ticks.push(ComponentTicks::new(tick));
+        if entity.index() >= self.sparse.len() {
+            self.sparse.resize(entity.index() + 1, None);
+        }
+        self.sparse[entity.index()] = Some(self.entity_ids.len() - 1);
     }
 
-    pub fn borrow_entity_ids(&self) -> &Vec<usize> {
+    pub fn borrow_entity_ids(&self) -> &Vec<Entity> {
         &self.entity_ids
     }
 
-    fn has(&self, entity_id: usize) -> bool {
-        self.entity_ids_map.contains_key(&entity_id)
+    fn index_of(&self, entity: Entity) -> Option<usize> {
+        let index = (*self.sparse.get(entity.index())?)?;
+        if self.entity_ids[index] == entity {
+            Some(index)
+        } else {
+            None
+        }
     }
 
-    pub fn remove(&mut self, entity_id: usize) {
-        if !self.has(entity_id) {
-            return;
-        }
+    fn has(&self, entity: Entity) -> bool {
+        self.index_of(entity).is_some()
+    }
+
+    pub fn remove(&mut self, entity: Entity, tick: u64) {
+        let index = match self.index_of(entity) {
+            Some(index) => index,
+            None => return,
+        };
 
-        let index = *self.entity_ids_map.get(&entity_id).unwrap();
-        self.entity_ids_map
-            .insert(*self.entity_ids.last().unwrap(), index);
+        self.sparse[self.entity_ids.last().unwrap().index()] = Some(index);
         self.components.swap_remove(index);
         self.entity_ids.swap_remove(index);
-        self.entity_ids_map.remove(&entity_id);
+        self.ticks.swap_remove(index);
+        self.sparse[entity.index()] = None;
+        self.removed.push((entity, tick));
     }
 
-    fn borrow_components_mut(&mut self, entity_id: usize) -> Option<&mut T> {
-        if !self.has(entity_id) {
-            return None;
+    pub fn ticks(&self, entity: Entity) -> Option<ComponentTicks> {
+        let index = self.index_of(entity)?;
+        Some(self.ticks[index])
+    }
+
+    /// Entities that lost their `T` after `since`, oldest first
+    pub fn removed_since(&self, since: u64) -> impl Iterator<Item = Entity> + '_ {
+        self.removed
+            .iter()
+            .filter(move |(_, tick)| *tick > since)
+            .map(|(entity, _)| *entity)
+    }
+
+    /// Swaps the handles of moved entities, the dense vectors keep their order
+    ///
+    /// Managers used as archetype columns never fill `sparse`, so it is only rebuilt for managers
+    /// that use it.
+    fn remap(&mut self, remap: &Remap, len: usize) {
+        for entity in self.entity_ids.iter_mut() {
+            *entity = remap.remap(*entity);
+        }
+        for (entity, _) in self.removed.iter_mut() {
+            *entity = remap.remap(*entity);
         }
-        let index = self.entity_ids_map.get(&entity_id).unwrap();
-        Some(&mut self.components[*index])
+        if !self.sparse.is_empty() {
+            self.sparse = vec![None; len];
+            for (index, entity) in self.entity_ids.iter().enumerate() {
+                self.sparse[entity.index()] = Some(index);
+            }
+        }
+        self.components.shrink_to_fit();
+        self.entity_ids.shrink_to_fit();
+        self.ticks.shrink_to_fit();
+        self.removed.shrink_to_fit();
+    }
+
+    fn push_row(&mut self, entity: Entity, component: T, ticks: ComponentTicks) {
+        self.components.push(component);
+        self.entity_ids.push(entity);
+        self.ticks.push(ticks);
     }
 
-    fn borrow_component(&self, entity_id: usize) -> Option<&T> {
-        if !self.has(entity_id) {
-            return None;
+    fn swap_remove_row(&mut self, row: usize) -> (T, ComponentTicks) {
+        self.entity_ids.swap_remove(row);
+        (
+            self.components.swap_remove(row),
+            self.ticks.swap_remove(row),
+        )
+    }
+
+    fn borrow_component(&self, entity: Entity) -> Option<&T> {
+        let index = self.index_of(entity)?;
+        Some(&self.components[index])
+    }
+
+    fn borrow_component_mut(&mut self, entity: Entity) -> Option<(&mut T, &mut ComponentTicks)> {
+        let index = self.index_of(entity)?;
+        Some((&mut self.components[index], &mut self.ticks[index]))
+    }
+}
+
+/// Membership set of the entities having the tag `T`
+///
+/// Tags carry no data, so only the entities are kept: packed in `entity_ids` with `sparse`
+/// indexed by entity index just like in `ComponentManager`. There are no change ticks and no
+/// removal log, so `Added`, `Changed` and `RemovedComponents` never see tags.
+pub struct TagManager<T> {
+    entity_ids: Vec<Entity>,
+    sparse: Vec<Option<usize>>,
+    marker: PhantomData<T>,
+}
+
+impl<T: Component> TagManager<T> {
+    pub fn new() -> Self {
+        Self {
+            entity_ids: vec![],
+            sparse: vec![],
+            marker: PhantomData,
         }
-        let index = self.entity_ids_map.get(&entity_id).unwrap();
-        Some(&self.components[*index])
     }
 
-    fn borrow_component_mut(&mut self, entity_id: usize) -> Option<&mut T> {
-        let index = self.entity_ids_map.get(&entity_id).unwrap();
-        Some(&mut self.components[*index])
+    /// Tags the entity, tagging it twice changes nothing
+    pub fn add(&mut self, entity: Entity) {
+        if self.has(entity) {
+            return;
+        }
+        self.entity_ids.push(entity);
+        if entity.index() >= self.sparse.len() {
+            self.sparse.resize(entity.index() + 1, None);
+        }
+        self.sparse[entity.index()] = Some(self.entity_ids.len() - 1);
+    }
+
+    fn index_of(&self, entity: Entity) -> Option<usize> {
+        let index = (*self.sparse.get(entity.index())?)?;
+        (self.entity_ids[index] == entity).then_some(index)
     }
 
-    fn borrow_components(&self) -> Option<&Vec<T>> {
-        Some(&self.components)
+    fn has(&self, entity: Entity) -> bool {
+        self.index_of(entity).is_some()
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        let index = match self.index_of(entity) {
+            Some(index) => index,
+            None => return,
+        };
+        self.sparse[self.entity_ids.last().unwrap().index()] = Some(index);
+        self.entity_ids.swap_remove(index);
+        self.sparse[entity.index()] = None;
+    }
+
+    fn push_row(&mut self, entity: Entity) {
+        self.entity_ids.push(entity);
+    }
+}
+
+impl<T: Component> Default for TagManager<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static + Component> ComponentManagerTrait for TagManager<T> {
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+
+    fn has(&self, entity: Entity) -> bool {
+        self.index_of(entity).is_some()
+    }
+
+    fn ticks(&self, _entity: Entity) -> Option<ComponentTicks> {
+        None
+    }
+
+    fn entity_ids(&self) -> &[Entity] {
+        &self.entity_ids
+    }
+
+    fn remove(&mut self, entity: Entity, _tick: u64) {
+        TagManager::remove(self, entity);
+    }
+
+    fn clear_removed(&mut self, _before: u64) {}
+
+    fn get_type_id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn type_name(&self) -> &'static str {
+        type_name::<T>()
+    }
+
+    fn new_empty(&self) -> Box<dyn ComponentManagerTrait> {
+        Box::new(TagManager::<T>::new())
+    }
+
+    fn row_ticks(&self, _row: usize) -> Option<ComponentTicks> {
+        None
+    }
+
+    fn move_row(&mut self, row: usize, to: &mut dyn ComponentManagerTrait) {
+        let entity = self.entity_ids.swap_remove(row);
+        cast_tags_mut::<T>(to).push_row(entity);
+    }
+
+    fn remove_row(&mut self, row: usize, _tick: u64) {
+        self.entity_ids.swap_remove(row);
+    }
+
+    fn remap(&mut self, remap: &Remap, len: usize) {
+        for entity in self.entity_ids.iter_mut() {
+            *entity = remap.remap(*entity);
+        }
+        if !self.sparse.is_empty() {
+            self.sparse = vec![None; len];
+            for (index, entity) in self.entity_ids.iter().enumerate() {
+                self.sparse[entity.index()] = Some(index);
+            }
+        }
+        self.entity_ids.shrink_to_fit();
+    }
+}
+
+pub fn cast_tags_mut<T: 'static + Component>(
+    manager: &mut dyn ComponentManagerTrait,
+) -> &mut TagManager<T> {
+    manager
+        .as_any_mut()
+        .downcast_mut::<TagManager<T>>()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Health(u8);
+
+    impl Component for Health {}
+
+    #[test]
+    pub fn should_swap_last_component_into_removed_slot() {
+        let mut manager = ComponentManager::<Health>::new();
+        let first = Entity::new(0, 0);
+        let second = Entity::new(1, 0);
+        let third = Entity::new(2, 0);
+        manager.add(first, Health(1), 0);
+        manager.add(second, Health(2), 0);
+        manager.add(third, Health(3), 0);
+
+        manager.remove(first, 0);
+
+        assert_eq!(manager.borrow_entity_ids(), &vec![third, second]);
+        assert_eq!(manager.borrow_component(third).unwrap().0, 3);
+        assert_eq!(manager.borrow_component(second).unwrap().0, 2);
+        assert!(manager.borrow_component(first).is_none());
+    }
+
+    #[test]
+    pub fn should_not_resolve_stale_handle() {
+        let mut manager = ComponentManager::<Health>::new();
+        let stale = Entity::new(0, 0);
+        let current = Entity::new(0, 1);
+        manager.add(current, Health(1), 0);
+
+        assert!(!manager.has(stale));
+        assert!(manager.borrow_component(stale).is_none());
+        assert!(manager.borrow_component_mut(stale).is_none());
+        assert!(manager.has(current));
+    }
+
+    #[test]
+    pub fn should_replace_component_when_added_twice() {
+        let mut manager = ComponentManager::<Health>::new();
+        let entity = Entity::new(0, 0);
+        manager.add(entity, Health(1), 1);
+        manager.add(entity, Health(2), 2);
+
+        assert_eq!(manager.borrow_entity_ids().len(), 1);
+        assert_eq!(manager.borrow_component(entity).unwrap().0, 2);
+        assert_eq!(
+            manager.ticks(entity),
+            Some(ComponentTicks {
+                added: 1,
+                changed: 2
+            })
+        );
+    }
+
+    #[test]
+    pub fn should_log_removed_components() {
+        let mut manager = ComponentManager::<Health>::new();
+        let first = Entity::new(0, 0);
+        let second = Entity::new(1, 0);
+        manager.add(first, Health(1), 0);
+        manager.add(second, Health(2), 0);
+        manager.remove(first, 3);
+        manager.remove(second, 5);
+
+        assert_eq!(manager.removed_since(3).collect::<Vec<_>>(), [second]);
+
+        manager.clear_removed(5);
+
+        assert_eq!(manager.removed_since(0).collect::<Vec<_>>(), [second]);
+    }
+
+    #[test]
+    pub fn should_keep_only_entities_for_tags() {
+        #[derive(Default)]
+        struct Frozen;
+        impl Component for Frozen {}
+        impl Tag for Frozen {}
+
+        let mut tags = TagManager::<Frozen>::new();
+        let first = Entity::new(0, 0);
+        let second = Entity::new(1, 0);
+        tags.add(first);
+        tags.add(second);
+        tags.add(first);
+        tags.remove(first);
+
+        assert_eq!(tags.entity_ids, [second]);
+        assert!(!ComponentManagerTrait::has(&tags, first));
+        assert_eq!(ComponentManagerTrait::ticks(&tags, second), None);
+        // Nothing but the dense entity ids and the sparse index, no values, ticks or removal log
+        assert_eq!(
+            size_of::<TagManager<Frozen>>(),
+            size_of::<Vec<Entity>>() + size_of::<Vec<Option<usize>>>()
+        );
     }
 }
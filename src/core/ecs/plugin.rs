@@ -0,0 +1,6 @@
+use super::simulation::Simulation;
+
+/// A bundle of setup for a `Simulation`, like the systems and resources of one game feature
+pub trait Plugin {
+    fn build(self, simulation: &mut Simulation);
+}
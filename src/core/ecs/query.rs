@@ -0,0 +1,679 @@
+use std::{
+    any::{type_name, TypeId},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{RwLockReadGuard, RwLockWriteGuard},
+};
+
+use super::{
+    cast_manager, cast_manager_mut,
+    entities::Entity,
+    entitymanager::EntityManager,
+    error::EcsError,
+    storage::{ComponentsIter, ComponentsRef, ComponentsRefMut},
+    Component, ComponentManager, ComponentManagerTrait, ComponentTicks,
+};
+
+/// Shared borrow of the `ComponentManager` for `T`
+pub struct ManagerRef<'a, T> {
+    guard: RwLockReadGuard<'a, Box<dyn ComponentManagerTrait>>,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T> ManagerRef<'a, T> {
+    pub(crate) fn new(guard: RwLockReadGuard<'a, Box<dyn ComponentManagerTrait>>) -> Self {
+        Self {
+            guard,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: 'static + Component> Deref for ManagerRef<'a, T> {
+    type Target = ComponentManager<T>;
+
+    fn deref(&self) -> &Self::Target {
+        cast_manager(&**self.guard)
+    }
+}
+
+/// Exclusive borrow of the `ComponentManager` for `T`
+pub struct ManagerRefMut<'a, T> {
+    guard: RwLockWriteGuard<'a, Box<dyn ComponentManagerTrait>>,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T> ManagerRefMut<'a, T> {
+    pub(crate) fn new(guard: RwLockWriteGuard<'a, Box<dyn ComponentManagerTrait>>) -> Self {
+        Self {
+            guard,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: 'static + Component> Deref for ManagerRefMut<'a, T> {
+    type Target = ComponentManager<T>;
+
+    fn deref(&self) -> &Self::Target {
+        cast_manager(&**self.guard)
+    }
+}
+
+impl<'a, T: 'static + Component> DerefMut for ManagerRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        cast_manager_mut(&mut **self.guard)
+    }
+}
+
+/// Mutable borrow of a component handed out by queries over `&mut T`
+///
+/// Writing through it marks the component as changed, only reading it does not.
+pub struct Mut<'a, T> {
+    component: &'a mut T,
+    ticks: &'a mut ComponentTicks,
+    tick: u64,
+}
+
+impl<'a, T> Deref for Mut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.component
+    }
+}
+
+impl<'a, T> DerefMut for Mut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.ticks.changed = self.tick;
+        self.component
+    }
+}
+
+/// A single component borrow that a query is going to make
+pub struct ComponentAccess {
+    type_id: TypeId,
+    type_name: &'static str,
+    mutable: bool,
+}
+
+impl ComponentAccess {
+    pub fn read<T: 'static>() -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            type_name: type_name::<T>(),
+            mutable: false,
+        }
+    }
+
+    pub fn write<T: 'static>() -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            type_name: type_name::<T>(),
+            mutable: true,
+        }
+    }
+}
+
+/// Something that can be fetched for an entity by a `Query`
+///
+/// Implemented for `&T`, `&mut T` and tuples of those. `State` holds the borrows of the
+/// component managers for as long as the query lives, `Item` is what gets handed out per entity.
+/// `&mut T` hands out a `Mut<T>` so writes are recorded for change detection.
+pub trait QueryData {
+    type State<'w>: 'w;
+    type Item<'s>;
+
+    fn access(access: &mut Vec<ComponentAccess>);
+    fn fetch_state(em: &EntityManager) -> Result<Self::State<'_>, EcsError>;
+
+    // Iteration walks the dense entity ids of the shortest column, table by table. `rows` is the
+    // length of that column in `table` and `entity_at` reads its ids, the `*_at` fetches read
+    // straight from `row` when the column lines up with it and probe by entity otherwise.
+    fn tables(state: &Self::State<'_>) -> usize;
+    fn rows(state: &Self::State<'_>, table: usize) -> usize;
+    fn entity_at(state: &Self::State<'_>, table: usize, row: usize) -> Entity;
+
+    fn fetch<'s>(state: &'s mut Self::State<'_>, entity: Entity) -> Option<Self::Item<'s>>;
+    fn fetch_at<'s>(
+        state: &'s mut Self::State<'_>,
+        table: usize,
+        row: usize,
+        entity: Entity,
+    ) -> Option<Self::Item<'s>>;
+}
+
+/// Query data that only ever reads, which lets several items be borrowed at once
+pub trait ReadOnlyQueryData: QueryData {
+    /// Iterator over every entity that has all components together with its components
+    type Iter<'s, 'w: 's>: Iterator<Item = (Entity, Self::Item<'s>)>;
+
+    fn iter<'s, 'w: 's>(state: &'s Self::State<'w>) -> Self::Iter<'s, 'w>;
+
+    fn fetch_read<'s>(state: &'s Self::State<'_>, entity: Entity) -> Option<Self::Item<'s>>;
+    fn fetch_read_at<'s>(
+        state: &'s Self::State<'_>,
+        table: usize,
+        row: usize,
+        entity: Entity,
+    ) -> Option<Self::Item<'s>>;
+}
+
+impl<T: 'static + Component> QueryData for &T {
+    type State<'w> = ComponentsRef<'w, T>;
+    type Item<'s> = &'s T;
+
+    fn access(access: &mut Vec<ComponentAccess>) {
+        access.push(ComponentAccess::read::<T>());
+    }
+
+    fn fetch_state(em: &EntityManager) -> Result<Self::State<'_>, EcsError> {
+        em.borrow_components::<T>()
+    }
+
+    fn tables(state: &Self::State<'_>) -> usize {
+        state.tables()
+    }
+
+    fn rows(state: &Self::State<'_>, table: usize) -> usize {
+        state.rows(table)
+    }
+
+    fn entity_at(state: &Self::State<'_>, table: usize, row: usize) -> Entity {
+        state.entity_at(table, row)
+    }
+
+    fn fetch<'s>(state: &'s mut Self::State<'_>, entity: Entity) -> Option<Self::Item<'s>> {
+        state.get(entity)
+    }
+
+    fn fetch_at<'s>(
+        state: &'s mut Self::State<'_>,
+        table: usize,
+        row: usize,
+        entity: Entity,
+    ) -> Option<Self::Item<'s>> {
+        state.get_at(table, row, entity)
+    }
+}
+
+impl<T: 'static + Component> ReadOnlyQueryData for &T {
+    type Iter<'s, 'w: 's> = ComponentsIter<'s, 'w, T>;
+
+    fn iter<'s, 'w: 's>(state: &'s Self::State<'w>) -> Self::Iter<'s, 'w> {
+        state.iter()
+    }
+
+    fn fetch_read<'s>(state: &'s Self::State<'_>, entity: Entity) -> Option<Self::Item<'s>> {
+        state.get(entity)
+    }
+
+    fn fetch_read_at<'s>(
+        state: &'s Self::State<'_>,
+        table: usize,
+        row: usize,
+        entity: Entity,
+    ) -> Option<Self::Item<'s>> {
+        state.get_at(table, row, entity)
+    }
+}
+
+impl<T: 'static + Component> QueryData for &mut T {
+    type State<'w> = (ComponentsRefMut<'w, T>, u64);
+    type Item<'s> = Mut<'s, T>;
+
+    fn access(access: &mut Vec<ComponentAccess>) {
+        access.push(ComponentAccess::write::<T>());
+    }
+
+    fn fetch_state(em: &EntityManager) -> Result<Self::State<'_>, EcsError> {
+        Ok((em.borrow_components_mut::<T>()?, em.change_tick()))
+    }
+
+    fn tables(state: &Self::State<'_>) -> usize {
+        state.0.tables()
+    }
+
+    fn rows(state: &Self::State<'_>, table: usize) -> usize {
+        state.0.rows(table)
+    }
+
+    fn entity_at(state: &Self::State<'_>, table: usize, row: usize) -> Entity {
+        state.0.entity_at(table, row)
+    }
+
+    fn fetch<'s>(state: &'s mut Self::State<'_>, entity: Entity) -> Option<Self::Item<'s>> {
+        let (manager, tick) = state;
+        let (component, ticks) = manager.get_mut(entity)?;
+        Some(Mut {
+            component,
+            ticks,
+            tick: *tick,
+        })
+    }
+
+    fn fetch_at<'s>(
+        state: &'s mut Self::State<'_>,
+        table: usize,
+        row: usize,
+        entity: Entity,
+    ) -> Option<Self::Item<'s>> {
+        let (manager, tick) = state;
+        let (component, ticks) = manager.get_mut_at(table, row, entity)?;
+        Some(Mut {
+            component,
+            ticks,
+            tick: *tick,
+        })
+    }
+}
+
+macro_rules! impl_query_data {
+    ($($name:ident),+) => {
+        impl<$($name: QueryData),+> QueryData for ($($name,)+) {
+            type State<'w> = ($($name::State<'w>,)+);
+            type Item<'s> = ($($name::Item<'s>,)+);
+
+            fn access(access: &mut Vec<ComponentAccess>) {
+                $($name::access(access);)+
+            }
+
+            fn fetch_state(em: &EntityManager) -> Result<Self::State<'_>, EcsError> {
+                Ok(($($name::fetch_state(em)?,)+))
+            }
+
+            #[allow(non_snake_case)]
+            fn tables(state: &Self::State<'_>) -> usize {
+                let ($($name,)+) = state;
+                [$($name::tables($name)),+].iter().copied().min().unwrap()
+            }
+
+            #[allow(non_snake_case)]
+            fn rows(state: &Self::State<'_>, table: usize) -> usize {
+                let ($($name,)+) = state;
+                [$($name::rows($name, table)),+].iter().copied().min().unwrap()
+            }
+
+            #[allow(non_snake_case)]
+            fn entity_at(state: &Self::State<'_>, table: usize, row: usize) -> Entity {
+                let rows = Self::rows(state, table);
+                let ($($name,)+) = state;
+                $(if $name::rows($name, table) == rows {
+                    return $name::entity_at($name, table, row);
+                })+
+                unreachable!()
+            }
+
+            #[allow(non_snake_case)]
+            fn fetch<'s>(state: &'s mut Self::State<'_>, entity: Entity) -> Option<Self::Item<'s>> {
+                let ($($name,)+) = state;
+                Some(($($name::fetch($name, entity)?,)+))
+            }
+
+            #[allow(non_snake_case)]
+            fn fetch_at<'s>(
+                state: &'s mut Self::State<'_>,
+                table: usize,
+                row: usize,
+                entity: Entity,
+            ) -> Option<Self::Item<'s>> {
+                let ($($name,)+) = state;
+                Some(($($name::fetch_at($name, table, row, entity)?,)+))
+            }
+        }
+
+        impl<$($name: ReadOnlyQueryData),+> ReadOnlyQueryData for ($($name,)+) {
+            type Iter<'s, 'w: 's> = Rows<'s, 'w, Self>;
+
+            fn iter<'s, 'w: 's>(state: &'s Self::State<'w>) -> Self::Iter<'s, 'w> {
+                Rows::new(state)
+            }
+
+            #[allow(non_snake_case)]
+            fn fetch_read<'s>(state: &'s Self::State<'_>, entity: Entity) -> Option<Self::Item<'s>> {
+                let ($($name,)+) = state;
+                Some(($($name::fetch_read($name, entity)?,)+))
+            }
+
+            #[allow(non_snake_case)]
+            fn fetch_read_at<'s>(
+                state: &'s Self::State<'_>,
+                table: usize,
+                row: usize,
+                entity: Entity,
+            ) -> Option<Self::Item<'s>> {
+                let ($($name,)+) = state;
+                Some(($($name::fetch_read_at($name, table, row, entity)?,)+))
+            }
+        }
+    };
+}
+
+impl_query_data!(A);
+impl_query_data!(A, B);
+impl_query_data!(A, B, C);
+impl_query_data!(A, B, C, D);
+impl_query_data!(A, B, C, D, E);
+impl_query_data!(A, B, C, D, E, F);
+
+/// Iterator over a query over several components, see `Query::iter`
+///
+/// The number of rows to walk is worked out once per table, every row then probes the other
+/// columns for the entity the shortest column holds there.
+pub struct Rows<'s, 'w, Q: QueryData> {
+    state: &'s Q::State<'w>,
+    table: usize,
+    row: usize,
+    rows: usize,
+}
+
+impl<'s, 'w, Q: QueryData> Rows<'s, 'w, Q> {
+    fn new(state: &'s Q::State<'w>) -> Self {
+        Self {
+            state,
+            table: 0,
+            row: 0,
+            rows: if Q::tables(state) > 0 {
+                Q::rows(state, 0)
+            } else {
+                0
+            },
+        }
+    }
+}
+
+impl<'s, 'w, Q: ReadOnlyQueryData> Iterator for Rows<'s, 'w, Q> {
+    type Item = (Entity, Q::Item<'s>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.row == self.rows {
+                self.table += 1;
+                if self.table >= Q::tables(self.state) {
+                    return None;
+                }
+                self.row = 0;
+                self.rows = Q::rows(self.state, self.table);
+                continue;
+            }
+            let row = self.row;
+            self.row += 1;
+            let entity = Q::entity_at(self.state, self.table, row);
+            if let Some(item) = Q::fetch_read_at(self.state, self.table, row, entity) {
+                return Some((entity, item));
+            }
+        }
+    }
+}
+
+/// Typed access to the components of entities
+///
+/// A query borrows every component manager it needs when it is created and keeps those borrows
+/// until it is dropped. A query that names the same component twice where one of them is mutable
+/// fails on creation with `EcsError::AliasingBorrow`, and so does creating a query while another
+/// live query holds a conflicting borrow of one of its components.
+pub struct Query<'w, Q: QueryData> {
+    state: Q::State<'w>,
+}
+
+impl<'w, Q: QueryData> Query<'w, Q> {
+    pub(crate) fn new(em: &'w EntityManager) -> Result<Self, EcsError> {
+        let mut access = vec![];
+        Q::access(&mut access);
+        check_aliasing(&access)?;
+        Ok(Self {
+            state: Q::fetch_state(em)?,
+        })
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<Q::Item<'_>> {
+        Q::fetch(&mut self.state, entity)
+    }
+
+    /// Calls `f` with every entity that has all components of the query
+    ///
+    /// Walks the dense ids of the shortest column and probes the other columns from there.
+    /// Items borrow the query mutably, so unlike `iter` this can not be an `Iterator`.
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(Entity, Q::Item<'_>)) {
+        for table in 0..Q::tables(&self.state) {
+            for row in 0..Q::rows(&self.state, table) {
+                let entity = Q::entity_at(&self.state, table, row);
+                if let Some(item) = Q::fetch_at(&mut self.state, table, row, entity) {
+                    f(entity, item);
+                }
+            }
+        }
+    }
+}
+
+impl<'w, Q: ReadOnlyQueryData> Query<'w, Q> {
+    pub fn get(&self, entity: Entity) -> Option<Q::Item<'_>> {
+        Q::fetch_read(&self.state, entity)
+    }
+
+    /// Every entity that has all components of the query together with its components
+    ///
+    /// Walks the dense ids of the shortest column and probes the other columns from there, a
+    /// query over a single component reads its column front to back without any lookups.
+    pub fn iter(&self) -> Q::Iter<'_, 'w> {
+        Q::iter(&self.state)
+    }
+}
+
+impl<'w, T: 'static + Component> Query<'w, &mut T> {
+    /// Every entity that has a `T` together with its `T`, straight from the dense vectors
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, Mut<'_, T>)> + use<'_, 'w, T> {
+        let (components, tick) = &mut self.state;
+        let tick = *tick;
+        components
+            .iter_mut()
+            .map(move |(entity, component, ticks)| {
+                (
+                    entity,
+                    Mut {
+                        component,
+                        ticks,
+                        tick,
+                    },
+                )
+            })
+    }
+}
+
+fn check_aliasing(access: &[ComponentAccess]) -> Result<(), EcsError> {
+    for (i, a) in access.iter().enumerate() {
+        for b in access[i + 1..].iter() {
+            if a.type_id == b.type_id && (a.mutable || b.mutable) {
+                return Err(EcsError::AliasingBorrow(a.type_name));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::ecs::storage::StorageMode;
+
+    struct Position(i16);
+    struct Velocity(i16);
+
+    impl Component for Position {}
+    impl Component for Velocity {}
+
+    fn setup() -> (EntityManager, Entity) {
+        setup_with(StorageMode::SparseSet)
+    }
+
+    fn setup_with(mode: StorageMode) -> (EntityManager, Entity) {
+        let mut em = EntityManager::with_storage(mode);
+        em.register::<Position>();
+        em.register::<Velocity>();
+        let entity = em.create_entity();
+        em.add_component_to_entity(entity, Position(1)).unwrap();
+        em.add_component_to_entity(entity, Velocity(2)).unwrap();
+        (em, entity)
+    }
+
+    #[test]
+    pub fn should_fetch_mixed_borrows() {
+        let (em, entity) = setup();
+        let mut query = em.query::<(&Velocity, &mut Position)>().unwrap();
+        let (velocity, mut position) = query.get_mut(entity).unwrap();
+        position.0 += velocity.0;
+
+        assert_eq!(query.get_mut(entity).unwrap().1 .0, 3);
+    }
+
+    #[test]
+    pub fn should_only_mark_written_components_as_changed() {
+        let (mut em, entity) = setup();
+        em.step_frame();
+        em.step_frame();
+        {
+            let mut query = em.query::<(&mut Velocity, &mut Position)>().unwrap();
+            let (velocity, mut position) = query.get_mut(entity).unwrap();
+            position.0 += velocity.0;
+        }
+
+        let changed = |ticks: Option<ComponentTicks>| ticks.unwrap().changed;
+        let positions = em.borrow_components::<Position>().unwrap();
+        let velocities = em.borrow_components::<Velocity>().unwrap();
+        assert_eq!(changed(positions.ticks(entity)), 3);
+        assert_eq!(changed(velocities.ticks(entity)), 1);
+    }
+
+    #[test]
+    pub fn should_fetch_from_archetype_tables() {
+        let (mut em, entity) = setup_with(StorageMode::Archetype);
+        let other = em.create_entity();
+        em.add_component_to_entity(other, Position(5)).unwrap();
+        em.remove_component::<Velocity>(entity).unwrap();
+        {
+            let mut query = em.query::<&mut Position>().unwrap();
+            query.get_mut(entity).unwrap().0 += 1;
+        }
+
+        let positions = em.query::<&Position>().unwrap();
+        assert_eq!(positions.get(entity).unwrap().0, 2);
+        assert_eq!(positions.get(other).unwrap().0, 5);
+        assert!(em.query::<&Velocity>().unwrap().get(entity).is_none());
+    }
+
+    #[test]
+    pub fn should_iterate_over_joined_components() {
+        for mode in [StorageMode::SparseSet, StorageMode::Archetype] {
+            let (mut em, entity) = setup_with(mode);
+            let other = em.create_entity();
+            em.add_component_to_entity(other, Position(5)).unwrap();
+
+            let query = em.query::<(&Position, &Velocity)>().unwrap();
+            let items = query
+                .iter()
+                .map(|(id, (position, velocity))| (id, position.0, velocity.0))
+                .collect::<Vec<_>>();
+            assert_eq!(items, [(entity, 1, 2)]);
+
+            let positions = em.query::<&Position>().unwrap();
+            assert_eq!(positions.iter().count(), 2);
+        }
+    }
+
+    #[test]
+    pub fn should_drive_joined_iteration_from_shortest_column() {
+        let (mut em, first) = setup();
+        let second = em.create_entity();
+        let third = em.create_entity();
+        em.add_component_to_entity(second, Position(2)).unwrap();
+        em.add_component_to_entity(third, Position(3)).unwrap();
+        em.add_component_to_entity(third, Velocity(3)).unwrap();
+        em.remove_component::<Velocity>(first).unwrap();
+        em.add_component_to_entity(first, Velocity(1)).unwrap();
+
+        // Velocity is the shorter column and holds `third` before `first`
+        let query = em.query::<(&Position, &Velocity)>().unwrap();
+        let ids = query.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids, [third, first]);
+        drop(query);
+
+        let mut ids = vec![];
+        em.query::<(&mut Position, &Velocity)>()
+            .unwrap()
+            .for_each_mut(|id, _| ids.push(id));
+        assert_eq!(ids, [third, first]);
+    }
+
+    #[test]
+    pub fn should_iterate_mutably() {
+        let (mut em, entity) = setup();
+        em.step_frame();
+        em.query::<(&Velocity, &mut Position)>()
+            .unwrap()
+            .for_each_mut(|_, (velocity, mut position)| position.0 += velocity.0);
+        for (_, mut velocity) in em.query::<&mut Velocity>().unwrap().iter_mut() {
+            velocity.0 *= 2;
+        }
+
+        let query = em.query::<(&Position, &Velocity)>().unwrap();
+        let (position, velocity) = query.get(entity).unwrap();
+        assert_eq!((position.0, velocity.0), (3, 4));
+        let velocities = em.borrow_components::<Velocity>();
+        assert_eq!(velocities.unwrap().ticks(entity).unwrap().changed, 2);
+    }
+
+    #[test]
+    pub fn should_return_none_when_component_is_missing() {
+        let (mut em, _) = setup();
+        let entity = em.create_entity();
+        em.add_component_to_entity(entity, Position(1)).unwrap();
+        let query = em.query::<(&Position, &Velocity)>().unwrap();
+
+        assert!(query.get(entity).is_none());
+    }
+
+    #[test]
+    pub fn should_allow_shared_borrows_of_same_component() {
+        let (em, entity) = setup();
+        let first = em.query::<&Position>().unwrap();
+        let second = em.query::<(&Position, &Velocity)>().unwrap();
+
+        assert_eq!(
+            first.get(entity).unwrap().0,
+            second.get(entity).unwrap().0 .0
+        );
+    }
+
+    #[test]
+    pub fn should_fail_on_aliasing_query() {
+        let (em, _) = setup();
+
+        assert_eq!(
+            em.query::<(&mut Position, &Position)>().err(),
+            Some(EcsError::AliasingBorrow(type_name::<Position>()))
+        );
+    }
+
+    #[test]
+    pub fn should_fail_on_conflicting_live_queries() {
+        let (em, _) = setup();
+        let _first = em.query::<&mut Position>().unwrap();
+
+        assert_eq!(
+            em.query::<&Position>().err(),
+            Some(EcsError::AliasingBorrow(type_name::<Position>()))
+        );
+    }
+
+    #[test]
+    pub fn should_fail_on_unregistered_component() {
+        struct Unregistered;
+        impl Component for Unregistered {}
+        let (em, _) = setup();
+
+        assert_eq!(
+            em.query::<(&Position, &Unregistered)>().err(),
+            Some(EcsError::UnregisteredComponent(type_name::<Unregistered>()))
+        );
+    }
+}
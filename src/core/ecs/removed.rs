@@ -0,0 +1,116 @@
+use std::marker::PhantomData;
+
+use super::{entities::Entity, entitymanager::EntityManager, error::EcsError, Component};
+
+/// Reads the entities that lost their `T`, owned by the system reading them
+///
+/// Works like an `EventReader`, every removal is handed out once per reader as long as the
+/// reader runs at least once every other tick. Despawning an entity counts as removing all of
+/// its components.
+///
+/// Removals made later in the frame of a read are stamped with the same tick as the ones it
+/// handed out, so the reader keeps those it handed out to tell them apart.
+pub struct RemovedComponents<T> {
+    last_read: u64,
+    read_at_last: Vec<Entity>,
+    marker: PhantomData<T>,
+}
+
+impl<T> Default for RemovedComponents<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RemovedComponents<T> {
+    pub fn new() -> Self {
+        Self {
+            last_read: 0,
+            read_at_last: vec![],
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: 'static + Component> RemovedComponents<T> {
+    pub fn read(&mut self, em: &EntityManager) -> Result<Vec<Entity>, EcsError> {
+        let last_read = self.last_read;
+        let mut removed = em
+            .borrow_components::<T>()?
+            .removed_since(last_read.saturating_sub(1));
+        let mut seen = self.read_at_last.clone();
+        removed.retain(|(entity, tick)| {
+            if *tick != last_read {
+                return true;
+            }
+            match seen.iter().position(|seen| seen == entity) {
+                Some(index) => {
+                    seen.swap_remove(index);
+                    false
+                }
+                None => true,
+            }
+        });
+
+        let tick = em.change_tick();
+        if tick != last_read {
+            self.read_at_last.clear();
+        }
+        self.read_at_last.extend(
+            removed
+                .iter()
+                .filter(|(_, removed_at)| *removed_at == tick)
+                .map(|(entity, _)| *entity),
+        );
+        self.last_read = tick;
+        Ok(removed.into_iter().map(|(entity, _)| entity).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Position;
+
+    impl Component for Position {}
+
+    #[test]
+    pub fn should_read_each_removal_once() {
+        let mut em = EntityManager::new();
+        em.register::<Position>();
+        let first = em.create_entity();
+        let second = em.create_entity();
+        em.add_component_to_entity(first, Position).unwrap();
+        em.add_component_to_entity(second, Position).unwrap();
+        let mut removed = RemovedComponents::<Position>::new();
+
+        em.remove_component::<Position>(first).unwrap();
+        assert_eq!(removed.read(&em).unwrap(), [first]);
+
+        em.step_frame();
+        em.remove_entity(second).unwrap();
+        assert_eq!(removed.read(&em).unwrap(), [second]);
+        assert!(removed.read(&em).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn should_read_removal_made_after_read_in_same_frame() {
+        let mut em = EntityManager::new();
+        em.register::<Position>();
+        let first = em.create_entity();
+        let second = em.create_entity();
+        em.add_component_to_entity(first, Position).unwrap();
+        em.add_component_to_entity(second, Position).unwrap();
+        let mut removed = RemovedComponents::<Position>::new();
+
+        em.step_frame();
+        em.remove_component::<Position>(first).unwrap();
+        assert_eq!(removed.read(&em).unwrap(), [first]);
+        em.remove_component::<Position>(second).unwrap();
+        assert_eq!(removed.read(&em).unwrap(), [second]);
+
+        em.step_frame();
+        assert!(removed.read(&em).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use super::{
+    error::EcsError,
+    system::{record_borrow, Borrow},
+};
+
+type AnyResource = Box<dyn Any + Send + Sync>;
+
+/// Singleton data that does not belong to any entity, like the arena or the score
+///
+/// Resources live behind a `RwLock` just like component managers, so systems can hold a
+/// resource and a query at the same time. Borrowing a resource that is already borrowed in a
+/// conflicting way fails with `EcsError::AliasingBorrow`.
+#[derive(Default)]
+pub struct Resources {
+    resource_map: HashMap<TypeId, RwLock<AnyResource>>,
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self {
+            resource_map: HashMap::new(),
+        }
+    }
+
+    pub fn insert<T: 'static + Send + Sync>(&mut self, resource: T) {
+        self.resource_map
+            .insert(TypeId::of::<T>(), RwLock::new(Box::new(resource)));
+    }
+
+    pub fn remove<T: 'static + Send + Sync>(&mut self) -> Option<T> {
+        let lock = self.resource_map.remove(&TypeId::of::<T>())?;
+        lock.into_inner()
+            .unwrap()
+            .downcast::<T>()
+            .ok()
+            .map(|resource| *resource)
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.resource_map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn get<T: 'static + Send + Sync>(&self) -> Result<Res<'_, T>, EcsError> {
+        let lock = self.lock::<T>()?;
+        record_borrow(Borrow::resource::<T>(false));
+        match lock.try_read() {
+            Ok(guard) => Ok(Res::new(guard)),
+            Err(error) => Err(EcsError::from_lock(error, type_name::<T>())),
+        }
+    }
+
+    pub fn get_mut<T: 'static + Send + Sync>(&self) -> Result<ResMut<'_, T>, EcsError> {
+        let lock = self.lock::<T>()?;
+        record_borrow(Borrow::resource::<T>(true));
+        match lock.try_write() {
+            Ok(guard) => Ok(ResMut::new(guard)),
+            Err(error) => Err(EcsError::from_lock(error, type_name::<T>())),
+        }
+    }
+
+    fn lock<T: 'static>(&self) -> Result<&RwLock<AnyResource>, EcsError> {
+        self.resource_map
+            .get(&TypeId::of::<T>())
+            .ok_or(EcsError::MissingResource(type_name::<T>()))
+    }
+}
+
+/// Shared borrow of a resource
+pub struct Res<'a, T> {
+    guard: RwLockReadGuard<'a, AnyResource>,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T> Res<'a, T> {
+    fn new(guard: RwLockReadGuard<'a, AnyResource>) -> Self {
+        Self {
+            guard,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: 'static> Deref for Res<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.downcast_ref::<T>().unwrap()
+    }
+}
+
+/// Exclusive borrow of a resource
+pub struct ResMut<'a, T> {
+    guard: RwLockWriteGuard<'a, AnyResource>,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T> ResMut<'a, T> {
+    fn new(guard: RwLockWriteGuard<'a, AnyResource>) -> Self {
+        Self {
+            guard,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: 'static> Deref for ResMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.downcast_ref::<T>().unwrap()
+    }
+}
+
+impl<'a, T: 'static> DerefMut for ResMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.downcast_mut::<T>().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+
+    struct Score(u32);
+
+    #[test]
+    pub fn should_mutate_resource() {
+        let mut resources = Resources::new();
+        resources.insert(Score(1));
+        resources.get_mut::<Score>().unwrap().0 += 1;
+
+        assert_eq!(resources.get::<Score>().unwrap().0, 2);
+    }
+
+    #[test]
+    pub fn should_return_none_for_missing_resource() {
+        let mut resources = Resources::new();
+        resources.insert(Score(1));
+
+        assert_eq!(resources.remove::<Score>().unwrap().0, 1);
+        assert_eq!(
+            resources.get::<Score>().err(),
+            Some(EcsError::MissingResource(type_name::<Score>()))
+        );
+        assert!(resources.remove::<Score>().is_none());
+    }
+
+    #[test]
+    pub fn should_fail_on_conflicting_borrow() {
+        let mut resources = Resources::new();
+        resources.insert(Score(1));
+        let aliasing = Some(EcsError::AliasingBorrow(type_name::<Score>()));
+
+        let score = resources.get_mut::<Score>();
+        assert_eq!(resources.get::<Score>().err(), aliasing);
+        assert_eq!(resources.get_mut::<Score>().err(), aliasing);
+        drop(score);
+
+        let _score = resources.get::<Score>();
+        assert!(resources.get::<Score>().is_ok());
+        assert_eq!(resources.get_mut::<Score>().err(), aliasing);
+    }
+
+    #[test]
+    pub fn should_report_borrow_poisoned_by_panic() {
+        let mut resources = Resources::new();
+        resources.insert(Score(1));
+        let poisoned = Some(EcsError::PoisonedBorrow(type_name::<Score>()));
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _score = resources.get_mut::<Score>().unwrap();
+            panic!("system panicked");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(resources.get::<Score>().err(), poisoned);
+        assert_eq!(resources.get_mut::<Score>().err(), poisoned);
+    }
+}
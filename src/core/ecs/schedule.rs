@@ -0,0 +1,570 @@
+use std::{collections::HashMap, panic};
+
+use super::{
+    command::Commands,
+    entityidaccessor::EntityIdAccessor,
+    entitymanager::EntityManager,
+    error::EcsError,
+    system::{System, SystemAccess},
+};
+
+#[cfg(debug_assertions)]
+use super::system::recording_borrows;
+
+/// The stages of a tick, run in the order they are declared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    Input,
+    Logic,
+    Collision,
+    Cleanup,
+    Render,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Input,
+        Stage::Logic,
+        Stage::Collision,
+        Stage::Cleanup,
+        Stage::Render,
+    ];
+}
+
+/// Condition deciding whether a system runs this tick
+///
+/// The access of a condition is added to the access of its system, so a condition never reads
+/// something a system in the same batch writes. Plain closures don't declare anything and are
+/// treated as exclusive.
+pub struct RunCondition {
+    condition: Box<dyn Fn(&EntityManager) -> bool>,
+    access: SystemAccess,
+}
+
+impl RunCondition {
+    pub fn new(access: SystemAccess, condition: impl Fn(&EntityManager) -> bool + 'static) -> Self {
+        Self {
+            condition: Box::new(condition),
+            access,
+        }
+    }
+}
+
+impl<F: Fn(&EntityManager) -> bool + 'static> From<F> for RunCondition {
+    fn from(condition: F) -> Self {
+        RunCondition::new(SystemAccess::exclusive(), condition)
+    }
+}
+
+/// A system together with where and when it should run
+///
+/// Every system owns its id cache and command queue so systems can run on separate threads.
+pub struct SystemConfig {
+    system: Box<dyn System>,
+    eia: EntityIdAccessor,
+    commands: Commands,
+    stage: Stage,
+    label: Option<&'static str>,
+    before: Vec<&'static str>,
+    after: Vec<&'static str>,
+    run_conditions: Vec<RunCondition>,
+}
+
+impl SystemConfig {
+    pub fn new<T: 'static + System>(system: T) -> Self {
+        Self {
+            system: Box::new(system),
+            eia: EntityIdAccessor::new(),
+            commands: Commands::new(),
+            stage: Stage::Logic,
+            label: None,
+            before: vec![],
+            after: vec![],
+            run_conditions: vec![],
+        }
+    }
+
+    pub fn stage(mut self, stage: Stage) -> Self {
+        self.stage = stage;
+        self
+    }
+
+    pub fn label(mut self, label: &'static str) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    /// Runs this system before the system labeled `label` if they share a stage
+    pub fn before(mut self, label: &'static str) -> Self {
+        self.before.push(label);
+        self
+    }
+
+    /// Runs this system after the system labeled `label` if they share a stage
+    pub fn after(mut self, label: &'static str) -> Self {
+        self.after.push(label);
+        self
+    }
+
+    /// Only runs the system when every condition added holds
+    pub fn run_if(mut self, condition: impl Into<RunCondition>) -> Self {
+        self.run_conditions.push(condition.into());
+        self
+    }
+
+    fn should_run(&self, em: &EntityManager) -> bool {
+        self.run_conditions
+            .iter()
+            .all(|condition| (condition.condition)(em))
+    }
+
+    fn access(&self) -> SystemAccess {
+        let mut access = self.system.access();
+        for condition in self.run_conditions.iter() {
+            access.extend(&condition.access);
+        }
+        access
+    }
+
+    /// Whether a `before`/`after` constraint exists between the two systems
+    fn is_ordered_with(&self, other: &SystemConfig) -> bool {
+        let constrains = |config: &SystemConfig, label: Option<&'static str>| {
+            label.is_some_and(|label| {
+                config.before.contains(&label) || config.after.contains(&label)
+            })
+        };
+        constrains(self, other.label) || constrains(other, self.label)
+    }
+}
+
+/// Run condition that holds while the resource `T` equals `value`
+pub fn resource_equals<T: 'static + Send + Sync + PartialEq>(value: T) -> RunCondition {
+    RunCondition::new(SystemAccess::new().read_resource::<T>(), move |em| {
+        em.resource::<T>().is_ok_and(|resource| *resource == value)
+    })
+}
+
+/// Orders systems into stages and runs them
+///
+/// Within a stage systems run in the order they were added unless `before`/`after` constraints
+/// say otherwise. Consecutive systems whose access does not conflict, and that are not ordered
+/// against each other, form a batch that runs on separate threads. Commands queued by the
+/// systems of a stage are applied in system order when the stage ends, so every stage starts from
+/// a settled `EntityManager` and the result does not depend on how threads were scheduled.
+pub struct Schedule {
+    systems: Vec<SystemConfig>,
+    order: Vec<Vec<Vec<usize>>>,
+    dirty: bool,
+    parallel: bool,
+    last_tick_start: u64,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self {
+            systems: vec![],
+            order: vec![],
+            dirty: false,
+            parallel: true,
+            last_tick_start: 0,
+        }
+    }
+
+    pub fn add_system(&mut self, config: SystemConfig) {
+        self.systems.push(config);
+        self.dirty = true;
+    }
+
+    /// Runs every batch one system at a time when `parallel` is false
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel;
+    }
+
+    /// Runs every system once and returns the errors of the commands that failed to apply
+    pub fn run(&mut self, em: &mut EntityManager) -> Vec<EcsError> {
+        if self.dirty {
+            self.order = self.build_order();
+            self.dirty = false;
+        }
+
+        // Every system has run once since the start of the previous tick, so removals logged
+        // before that have been seen by everyone.
+        em.clear_removed(self.last_tick_start);
+        self.last_tick_start = em.change_tick();
+
+        let mut errors = vec![];
+        apply_hook_commands(em, &mut errors);
+        for stage in self.order.iter() {
+            for batch in stage.iter() {
+                let running = batch
+                    .iter()
+                    .copied()
+                    .filter(|index| self.systems[*index].should_run(em))
+                    .collect::<Vec<_>>();
+                run_batch(&mut self.systems, &running, em, self.parallel);
+                for index in running.iter() {
+                    self.systems[*index].eia.set_last_run(em.change_tick());
+                }
+                for _ in batch.iter() {
+                    em.step_frame();
+                }
+            }
+            for index in stage.iter().flatten() {
+                errors.extend(self.systems[*index].commands.apply(em));
+            }
+            apply_hook_commands(em, &mut errors);
+        }
+        errors
+    }
+
+    fn build_order(&self) -> Vec<Vec<Vec<usize>>> {
+        Stage::ALL
+            .iter()
+            .map(|stage| {
+                let indices = (0..self.systems.len())
+                    .filter(|index| self.systems[*index].stage == *stage)
+                    .collect::<Vec<_>>();
+                self.batch_stage(&self.sort_stage(*stage, &indices))
+            })
+            .collect()
+    }
+
+    /// Splits the sorted systems of a stage into batches that may run at the same time
+    fn batch_stage(&self, sorted: &[usize]) -> Vec<Vec<usize>> {
+        let mut batches: Vec<Vec<usize>> = vec![];
+        let mut batch_access = SystemAccess::new();
+        for index in sorted.iter() {
+            let config = &self.systems[*index];
+            let access = config.access();
+            let fits = batches.last().is_some_and(|batch| {
+                !access.conflicts_with(&batch_access)
+                    && batch
+                        .iter()
+                        .all(|other| !config.is_ordered_with(&self.systems[*other]))
+            });
+            if fits {
+                batches.last_mut().unwrap().push(*index);
+                batch_access.extend(&access);
+            } else {
+                batches.push(vec![*index]);
+                batch_access = access;
+            }
+        }
+        batches
+    }
+
+    /// Topological sort of the systems in a stage, ties are broken by insertion order
+    fn sort_stage(&self, stage: Stage, indices: &[usize]) -> Vec<usize> {
+        let labels: HashMap<&str, usize> = indices
+            .iter()
+            .filter_map(|index| self.systems[*index].label.map(|label| (label, *index)))
+            .collect();
+
+        let mut dependencies: HashMap<usize, Vec<usize>> =
+            indices.iter().map(|index| (*index, vec![])).collect();
+        for index in indices.iter() {
+            let config = &self.systems[*index];
+            for label in config.after.iter() {
+                if let Some(other) = labels.get(label) {
+                    dependencies.get_mut(index).unwrap().push(*other);
+                }
+            }
+            for label in config.before.iter() {
+                if let Some(other) = labels.get(label) {
+                    dependencies.get_mut(other).unwrap().push(*index);
+                }
+            }
+        }
+
+        let mut sorted = vec![];
+        let mut remaining = indices.to_vec();
+        while !remaining.is_empty() {
+            let next = remaining.iter().position(|index| {
+                dependencies[index]
+                    .iter()
+                    .all(|dependency| sorted.contains(dependency))
+            });
+            match next {
+                Some(position) => sorted.push(remaining.remove(position)),
+                None => panic!(
+                    "systems in stage {:?} have cyclic ordering constraints",
+                    stage
+                ),
+            }
+        }
+        sorted
+    }
+}
+
+/// Applies what hooks queued until applying no longer makes hooks queue anything
+fn apply_hook_commands(em: &mut EntityManager, errors: &mut Vec<EcsError>) {
+    loop {
+        let mut commands = em.take_hook_commands();
+        if commands.is_empty() {
+            return;
+        }
+        errors.extend(commands.apply(em));
+    }
+}
+
+fn run_batch(systems: &mut [SystemConfig], running: &[usize], em: &EntityManager, parallel: bool) {
+    if !parallel || running.len() < 2 {
+        for index in running.iter() {
+            let config = &mut systems[*index];
+            let access = config.access();
+            run_system(
+                config.system.as_mut(),
+                &mut config.eia,
+                &mut config.commands,
+                &access,
+                em,
+            );
+        }
+        return;
+    }
+
+    crossbeam::scope(|scope| {
+        let handles = systems
+            .iter_mut()
+            .enumerate()
+            .filter(|(index, _)| running.contains(index))
+            .map(|(_, config)| {
+                let access = config.access();
+                let SystemConfig {
+                    system,
+                    eia,
+                    commands,
+                    ..
+                } = config;
+                scope.spawn(move |_| run_system(system.as_mut(), eia, commands, &access, em))
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            if let Err(payload) = handle.join() {
+                panic::resume_unwind(payload);
+            }
+        }
+    })
+    .unwrap();
+}
+
+/// Runs the system once, debug builds also check that it only borrowed what `access` declares
+fn run_system(
+    system: &mut dyn System,
+    eia: &mut EntityIdAccessor,
+    commands: &mut Commands,
+    access: &SystemAccess,
+    em: &EntityManager,
+) {
+    #[cfg(not(debug_assertions))]
+    {
+        let _ = access;
+        system.update(em, eia, commands);
+    }
+
+    #[cfg(debug_assertions)]
+    {
+        let borrows = recording_borrows(|| system.update(em, eia, commands));
+        if let Some(borrow) = borrows.iter().find(|borrow| !access.allows(borrow)) {
+            panic!(
+                "{} borrowed the {} {}{} without declaring it in its access",
+                system.name(),
+                if borrow.resource {
+                    "resource"
+                } else {
+                    "component"
+                },
+                borrow.type_name,
+                if borrow.mutable { " mutably" } else { "" },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::core::ecs::Component;
+
+    use super::*;
+
+    struct Position;
+    struct Velocity;
+
+    impl Component for Position {}
+    impl Component for Velocity {}
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    struct Recorder {
+        name: &'static str,
+        log: Log,
+        access: SystemAccess,
+    }
+
+    impl System for Recorder {
+        fn update(
+            &mut self,
+            _em: &EntityManager,
+            _eia: &mut EntityIdAccessor,
+            commands: &mut Commands,
+        ) {
+            self.log.lock().unwrap().push(self.name);
+            commands.spawn().insert(Position);
+        }
+
+        fn access(&self) -> SystemAccess {
+            self.access.clone()
+        }
+    }
+
+    fn recorder(name: &'static str, log: &Log) -> SystemConfig {
+        recorder_with(name, log, SystemAccess::exclusive())
+    }
+
+    fn recorder_with(name: &'static str, log: &Log, access: SystemAccess) -> SystemConfig {
+        SystemConfig::new(Recorder {
+            name,
+            log: log.clone(),
+            access,
+        })
+        .label(name)
+    }
+
+    fn setup() -> EntityManager {
+        let mut em = EntityManager::new();
+        em.register::<Position>();
+        em
+    }
+
+    #[test]
+    pub fn should_run_stages_in_order() {
+        let log = Log::default();
+        let mut schedule = Schedule::new();
+        schedule.add_system(recorder("render", &log).stage(Stage::Render));
+        schedule.add_system(recorder("input", &log).stage(Stage::Input));
+        schedule.add_system(recorder("logic", &log));
+        schedule.run(&mut setup());
+
+        assert_eq!(*log.lock().unwrap(), ["input", "logic", "render"]);
+    }
+
+    #[test]
+    pub fn should_respect_before_and_after() {
+        let log = Log::default();
+        let mut schedule = Schedule::new();
+        schedule.add_system(recorder("wrap", &log).after("move"));
+        schedule.add_system(recorder("move", &log));
+        schedule.add_system(recorder("steer", &log).before("move"));
+        schedule.run(&mut setup());
+
+        assert_eq!(*log.lock().unwrap(), ["steer", "move", "wrap"]);
+    }
+
+    #[test]
+    pub fn should_skip_systems_whose_condition_fails() {
+        #[derive(PartialEq)]
+        enum State {
+            Playing,
+            Paused,
+        }
+
+        let log = Log::default();
+        let mut em = setup();
+        em.insert_resource(State::Paused);
+        let mut schedule = Schedule::new();
+        schedule.add_system(recorder("move", &log).run_if(resource_equals(State::Playing)));
+        schedule.add_system(recorder("render", &log));
+        schedule.run(&mut em);
+
+        assert_eq!(*log.lock().unwrap(), ["render"]);
+    }
+
+    #[cfg(debug_assertions)]
+    struct Mover;
+
+    #[cfg(debug_assertions)]
+    impl System for Mover {
+        fn update(
+            &mut self,
+            em: &EntityManager,
+            _eia: &mut EntityIdAccessor,
+            _commands: &mut Commands,
+        ) {
+            em.borrow_components_mut::<Position>().unwrap();
+        }
+
+        fn access(&self) -> SystemAccess {
+            SystemAccess::new().read::<Position>()
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "borrowed the component")]
+    pub fn should_panic_on_undeclared_borrow() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(SystemConfig::new(Mover));
+        schedule.run(&mut setup());
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn should_panic_on_cycle() {
+        let log = Log::default();
+        let mut schedule = Schedule::new();
+        schedule.add_system(recorder("a", &log).after("b"));
+        schedule.add_system(recorder("b", &log).after("a"));
+        schedule.run(&mut setup());
+    }
+
+    #[test]
+    pub fn should_batch_systems_without_conflicts() {
+        let log = Log::default();
+        let mut schedule = Schedule::new();
+        let reads = || SystemAccess::new().read::<Position>();
+        schedule.add_system(recorder_with("a", &log, reads()));
+        schedule.add_system(recorder_with("b", &log, reads().write::<Velocity>()));
+        schedule.add_system(recorder_with(
+            "c",
+            &log,
+            SystemAccess::new().write::<Velocity>(),
+        ));
+        schedule.add_system(recorder_with("d", &log, reads()).after("c"));
+        schedule.add_system(recorder("e", &log));
+        schedule.run(&mut setup());
+
+        let logic = &schedule.order[Stage::Logic as usize];
+        assert_eq!(*logic, vec![vec![0, 1], vec![2], vec![3], vec![4]]);
+        assert_eq!(log.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    pub fn should_apply_commands_in_system_order() {
+        let log = Log::default();
+        let mut em = setup();
+        let mut schedule = Schedule::new();
+        let access = || SystemAccess::new().read::<Velocity>();
+        schedule.add_system(recorder_with("a", &log, access()));
+        schedule.add_system(recorder_with("b", &log, access()));
+        schedule.add_system(recorder_with("c", &log, access()));
+        schedule.run(&mut em);
+
+        let ids = em
+            .borrow_components::<Position>()
+            .unwrap()
+            .entity_ids()
+            .iter()
+            .map(|entity| entity.index())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [0, 1, 2]);
+        assert_eq!(schedule.order[Stage::Logic as usize], vec![vec![0, 1, 2]]);
+    }
+}
@@ -1,33 +1,72 @@
-use crate::core::gui::event_handler::{Action, EventHandler};
+use crate::core::gui::input::{Input, InputSource, ScriptedInput};
 
 use super::{
-    entityidaccessor::EntityIdAccessor, entitymanager::EntityManager, system::System, Component,
+    bundle::Bundle,
+    command::Commands,
+    entities::{Compaction, Entity, Remap, RemapEntities},
+    entitymanager::EntityManager,
+    error::EcsError,
+    event::Events,
+    plugin::Plugin,
+    resource::{Res, ResMut},
+    schedule::{Schedule, Stage, SystemConfig},
+    storage::StorageMode,
+    system::System,
+    Component, Tag,
 };
 
-#[derive(Default)]
 pub struct Simulation {
     pub entity_manager: EntityManager,
-    pub entity_id_accessor: EntityIdAccessor,
-    pub event_handler: EventHandler,
-    systems: Vec<Box<dyn System>>,
+    input: Box<dyn InputSource>,
+    tick: u64,
+    schedule: Schedule,
+    event_updaters: Vec<fn(&EntityManager)>,
+    errors: Vec<EcsError>,
+    compaction: Option<(u64, Compaction)>,
+    last_compaction: u64,
+    remap: Remap,
 }
 
 impl Simulation {
+    /// Creates a simulation without any input, see `set_input`
     pub fn new() -> Self {
+        Self::with_storage(StorageMode::SparseSet)
+    }
+
+    /// Creates a simulation whose components are laid out with `mode`
+    pub fn with_storage(mode: StorageMode) -> Self {
+        let mut entity_manager = EntityManager::with_storage(mode);
+        entity_manager.insert_resource(Input::default());
         Self {
-            entity_manager: EntityManager::new(),
-            entity_id_accessor: EntityIdAccessor::new(),
-            event_handler: EventHandler::new(),
-            systems: vec![],
+            entity_manager,
+            input: Box::new(ScriptedInput::default()),
+            tick: 0,
+            schedule: Schedule::new(),
+            event_updaters: vec![],
+            errors: vec![],
+            compaction: None,
+            last_compaction: 0,
+            remap: Remap::default(),
         }
     }
 
-    pub fn create_entity(&mut self) -> usize {
+    /// Replaces where the actions in the `Input` resource come from
+    pub fn set_input<I: 'static + InputSource>(&mut self, input: I) -> &mut Self {
+        self.input = Box::new(input);
+        self
+    }
+
+    pub fn create_entity(&mut self) -> Entity {
         self.entity_manager.create_entity()
     }
 
-    pub fn remove_entity(&mut self, entity_id: usize) {
-        self.entity_manager.remove_entity(entity_id);
+    /// Creates an entity with every component in `bundle`, for example `(Position, Collidable)`
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) -> Result<Entity, EcsError> {
+        self.entity_manager.spawn(bundle)
+    }
+
+    pub fn remove_entity(&mut self, entity: Entity) -> Result<(), EcsError> {
+        self.entity_manager.remove_entity(entity)
     }
 
     pub fn register_component<T: 'static + Component>(&mut self) -> &mut Self {
@@ -35,37 +74,229 @@ impl Simulation {
         self
     }
 
+    /// Registers a zero-sized marker component, see `Tag`
+    pub fn register_tag<T: 'static + Tag>(&mut self) -> &mut Self {
+        self.entity_manager.register_tag::<T>();
+        self
+    }
+
+    /// Runs `hook` whenever a `T` is added to an entity, see `EntityManager::on_add`
+    pub fn on_add<T: 'static + Component>(
+        &mut self,
+        hook: impl Fn(&EntityManager, Entity, &mut Commands) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.entity_manager.on_add::<T>(hook);
+        self
+    }
+
+    /// Runs `hook` whenever a `T` is removed from an entity, see `EntityManager::on_remove`
+    pub fn on_remove<T: 'static + Component>(
+        &mut self,
+        hook: impl Fn(&EntityManager, Entity, &mut Commands) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.entity_manager.on_remove::<T>(hook);
+        self
+    }
+
+    pub fn insert_resource<T: 'static + Send + Sync>(&mut self, resource: T) -> &mut Self {
+        self.entity_manager.insert_resource(resource);
+        self
+    }
+
+    pub fn resource<T: 'static + Send + Sync>(&self) -> Result<Res<'_, T>, EcsError> {
+        self.entity_manager.resource::<T>()
+    }
+
+    pub fn resource_mut<T: 'static + Send + Sync>(&self) -> Result<ResMut<'_, T>, EcsError> {
+        self.entity_manager.resource_mut::<T>()
+    }
+
+    /// Registers an event type, its buffers get swapped at the start of every `update`
+    pub fn add_event<T: 'static + Send + Sync>(&mut self) -> &mut Self {
+        if !self.entity_manager.has_resource::<Events<T>>() {
+            self.entity_manager.insert_resource(Events::<T>::default());
+            self.event_updaters.push(update_events::<T>);
+        }
+        self
+    }
+
+    /// Adds a system to the `Logic` stage
     pub fn add_system<T: 'static + System>(&mut self, system: T) -> &mut Self {
-        self.systems.push(Box::new(system));
+        self.add_system_config(SystemConfig::new(system))
+    }
+
+    pub fn add_system_to_stage<T: 'static + System>(
+        &mut self,
+        stage: Stage,
+        system: T,
+    ) -> &mut Self {
+        self.add_system_config(SystemConfig::new(system).stage(stage))
+    }
+
+    /// Adds a system with a stage, a label, ordering constraints and run conditions
+    pub fn add_system_config(&mut self, config: SystemConfig) -> &mut Self {
+        self.schedule.add_system(config);
+        self
+    }
+
+    /// Runs systems with non conflicting access on separate threads, enabled by default
+    pub fn set_parallel(&mut self, parallel: bool) -> &mut Self {
+        self.schedule.set_parallel(parallel);
         self
     }
 
+    pub fn add_plugin<P: Plugin>(&mut self, plugin: P) -> &mut Self {
+        plugin.build(self);
+        self
+    }
+
+    pub fn remove_component<T: 'static + Component>(
+        &mut self,
+        entity: Entity,
+    ) -> Result<(), EcsError> {
+        self.entity_manager.remove_component::<T>(entity)
+    }
+
+    pub fn has_component<T: 'static + Component>(&self, entity: Entity) -> Result<bool, EcsError> {
+        self.entity_manager.has_component::<T>(entity)
+    }
+
     pub fn add_component_to_entity<T: 'static + Component>(
         &mut self,
-        entity_id: usize,
+        entity: Entity,
         component: T,
-    ) -> &mut Self {
+    ) -> Result<(), EcsError> {
         self.entity_manager
-            .add_component_to_entity(entity_id, component);
+            .add_component_to_entity(entity, component)
+    }
+
+    /// Compacts the entity pool at the end of an `update` once every `updates` updates
+    ///
+    /// Compaction is off unless this is called. It moves entities to new handles, so every
+    /// component, resource and event type holding handles has to be registered with
+    /// `remap_component`, `remap_resource` or `remap_event` first. Any other handle kept across
+    /// an `update` is invalid after a compaction, `last_remap` tells what it became.
+    pub fn compact_every(&mut self, updates: u64, compaction: Compaction) -> &mut Self {
+        self.compaction = Some((updates, compaction));
+        self
+    }
+
+    /// Swaps the handles held by every `T` whenever the entity pool is compacted
+    pub fn remap_component<T: 'static + Component + RemapEntities>(&mut self) -> &mut Self {
+        self.entity_manager.remap_component::<T>();
+        self
+    }
+
+    /// Swaps the handles held by the resource `T` whenever the entity pool is compacted
+    pub fn remap_resource<T: 'static + Send + Sync + RemapEntities>(&mut self) -> &mut Self {
+        self.entity_manager.remap_resource::<T>();
+        self
+    }
+
+    /// Swaps the handles held by pending events of type `T` whenever the entity pool is compacted
+    pub fn remap_event<T: 'static + Send + Sync + RemapEntities>(&mut self) -> &mut Self {
+        self.entity_manager.remap_resource::<Events<T>>();
         self
     }
 
-    pub fn update(&mut self) -> Action {
-        let event = if let Ok(event) = self.event_handler.receiver.try_recv() {
-            event
-        } else {
-            Action::None
-        };
-
-        for system in self.systems.iter_mut() {
-            system.update(
-                &mut self.entity_manager,
-                &mut self.entity_id_accessor,
-                &event,
-            );
-            self.entity_manager.step_frame();
+    /// Report of the last compaction, empty when the last `update` did not compact
+    pub fn last_remap(&self) -> &Remap {
+        &self.remap
+    }
+
+    /// Errors of the commands that failed to apply during the last `update`
+    pub fn errors(&self) -> &[EcsError] {
+        &self.errors
+    }
+
+    /// Runs the systems once, the first `update` is tick 0 of the input
+    ///
+    /// Everything the input has pending is drained into the `Input` resource first.
+    pub fn update(&mut self) {
+        if let Ok(mut input) = self.entity_manager.resource_mut::<Input>() {
+            input.drain(self.input.as_mut(), self.tick);
+        }
+        self.tick += 1;
+        self.entity_manager.step_update();
+
+        for updater in self.event_updaters.iter() {
+            updater(&self.entity_manager);
+        }
+
+        self.errors = self.schedule.run(&mut self.entity_manager);
+
+        self.remap = Remap::default();
+        if let Some((updates, compaction)) = self.compaction {
+            let update = self.entity_manager.get_update();
+            if update - self.last_compaction >= updates {
+                self.remap = self.entity_manager.compact(compaction);
+                self.last_compaction = update;
+            }
+        }
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn update_events<T: 'static + Send + Sync>(em: &EntityManager) {
+    if let Ok(mut events) = em.resource_mut::<Events<T>>() {
+        events.update();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::ecs::{entityidaccessor::EntityIdAccessor, system::SystemAccess};
+
+    use super::*;
+
+    struct Position;
+
+    impl Component for Position {}
+
+    struct Idle;
+
+    impl System for Idle {
+        fn update(
+            &mut self,
+            _em: &EntityManager,
+            _eia: &mut EntityIdAccessor,
+            _commands: &mut Commands,
+        ) {
         }
 
-        event
+        fn access(&self) -> SystemAccess {
+            SystemAccess::new()
+        }
+    }
+
+    #[test]
+    pub fn should_count_compaction_intervals_in_updates() {
+        let mut simulation = Simulation::new();
+        simulation.register_component::<Position>();
+        simulation
+            .add_system(Idle)
+            .add_system(Idle)
+            .add_system(Idle);
+        simulation.compact_every(3, Compaction { min_idle: 2 });
+        let first = simulation.spawn((Position,)).unwrap();
+        let second = simulation.spawn((Position,)).unwrap();
+        simulation.update();
+        simulation.update();
+        simulation.remove_entity(first).unwrap();
+
+        // Compacts on updates 3 and 6, the slot has only been dead for one update on the first
+        let mut compacted = vec![];
+        for update in 3..=7 {
+            simulation.update();
+            if simulation.last_remap().get(second).is_some() {
+                compacted.push(update);
+            }
+        }
+        assert_eq!(compacted, [6]);
     }
 }
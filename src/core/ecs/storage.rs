@@ -0,0 +1,516 @@
+use std::{
+    any::{type_name, TypeId},
+    collections::HashMap,
+    iter::{Copied, Zip},
+    slice,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+use super::{
+    archetype::{Archetypes, Column},
+    cast_manager_mut,
+    entities::{Entity, Remap},
+    error::EcsError,
+    query::{ManagerRef, ManagerRefMut},
+    system::{record_borrow, Borrow},
+    Component, ComponentManager, ComponentManagerTrait, ComponentTicks, Tag, TagManager,
+};
+
+/// How an `EntityManager` lays out components in memory
+///
+/// `SparseSet` keeps one `ComponentManager` per type, which makes adding and removing components
+/// cheap. `Archetype` keeps entities with the same set of types together in tables, which makes
+/// iterating over several types at once cheap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageMode {
+    #[default]
+    SparseSet,
+    Archetype,
+}
+
+pub(crate) enum Storage {
+    /// One column per type, next to the name of the type for error reporting
+    SparseSet(HashMap<TypeId, (&'static str, Column)>),
+    Archetype(Archetypes),
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new(StorageMode::default())
+    }
+}
+
+impl Storage {
+    pub fn new(mode: StorageMode) -> Self {
+        match mode {
+            StorageMode::SparseSet => Storage::SparseSet(HashMap::new()),
+            StorageMode::Archetype => Storage::Archetype(Archetypes::new()),
+        }
+    }
+
+    pub fn mode(&self) -> StorageMode {
+        match self {
+            Storage::SparseSet(_) => StorageMode::SparseSet,
+            Storage::Archetype(_) => StorageMode::Archetype,
+        }
+    }
+
+    pub fn register<T: 'static + Component>(&mut self) {
+        match self {
+            Storage::SparseSet(managers) => {
+                managers.insert(
+                    TypeId::of::<T>(),
+                    (
+                        type_name::<T>(),
+                        RwLock::new(Box::new(ComponentManager::<T>::new())),
+                    ),
+                );
+            }
+            Storage::Archetype(archetypes) => archetypes.register::<T>(),
+        }
+    }
+
+    pub fn register_tag<T: 'static + Tag>(&mut self) {
+        match self {
+            Storage::SparseSet(managers) => {
+                managers.insert(
+                    TypeId::of::<T>(),
+                    (
+                        type_name::<T>(),
+                        RwLock::new(Box::new(TagManager::<T>::new())),
+                    ),
+                );
+            }
+            Storage::Archetype(archetypes) => archetypes.register_tag::<T>(),
+        }
+    }
+
+    pub fn check_registered<T: 'static + Component>(&self) -> Result<(), EcsError> {
+        let type_id = TypeId::of::<T>();
+        let registered = match self {
+            Storage::SparseSet(managers) => managers.contains_key(&type_id),
+            Storage::Archetype(archetypes) => archetypes.is_registered(&type_id),
+        };
+        match registered {
+            true => Ok(()),
+            false => Err(EcsError::UnregisteredComponent(type_name::<T>())),
+        }
+    }
+
+    pub fn insert<T: 'static + Component>(
+        &mut self,
+        entity: Entity,
+        component: T,
+        tick: u64,
+    ) -> Result<(), EcsError> {
+        self.check_registered::<T>()?;
+        match self {
+            Storage::SparseSet(managers) => {
+                let (_, manager) = managers.get_mut(&TypeId::of::<T>()).unwrap();
+                let manager = manager.get_mut().unwrap().as_mut();
+                match manager.as_any_mut().downcast_mut::<TagManager<T>>() {
+                    Some(tags) => tags.add(entity),
+                    None => cast_manager_mut::<T>(manager).add(entity, component, tick),
+                }
+            }
+            Storage::Archetype(archetypes) => archetypes.insert(entity, component, tick),
+        }
+        Ok(())
+    }
+
+    /// Removes the `T` of the entity, returns false if the entity did not have one
+    pub fn remove<T: 'static + Component>(
+        &mut self,
+        entity: Entity,
+        tick: u64,
+    ) -> Result<bool, EcsError> {
+        self.check_registered::<T>()?;
+        let type_id = TypeId::of::<T>();
+        match self {
+            Storage::SparseSet(managers) => {
+                let manager = managers.get_mut(&type_id).unwrap().1.get_mut().unwrap();
+                let has = manager.has(entity);
+                manager.remove(entity, tick);
+                Ok(has)
+            }
+            Storage::Archetype(archetypes) => Ok(archetypes.remove(entity, &type_id, tick)),
+        }
+    }
+
+    /// Removes all components of the entity and returns their types
+    pub fn remove_entity(&mut self, entity: Entity, tick: u64) -> Vec<TypeId> {
+        match self {
+            Storage::SparseSet(managers) => {
+                let mut removed = vec![];
+                for (type_id, (_, manager)) in managers.iter_mut() {
+                    let manager = manager.get_mut().unwrap();
+                    if manager.has(entity) {
+                        manager.remove(entity, tick);
+                        removed.push(*type_id);
+                    }
+                }
+                removed
+            }
+            Storage::Archetype(archetypes) => archetypes.remove_entity(entity, tick),
+        }
+    }
+
+    pub fn has(&self, entity: Entity, type_id: &TypeId) -> Result<bool, EcsError> {
+        match self {
+            Storage::SparseSet(managers) => match managers.get(type_id) {
+                Some((name, manager)) => Ok(read_column(manager, name)?.has(entity)),
+                None => Ok(false),
+            },
+            Storage::Archetype(archetypes) => Ok(archetypes.has(entity, type_id)),
+        }
+    }
+
+    pub fn ticks(
+        &self,
+        entity: Entity,
+        type_id: &TypeId,
+    ) -> Result<Option<ComponentTicks>, EcsError> {
+        match self {
+            Storage::SparseSet(managers) => match managers.get(type_id) {
+                Some((name, manager)) => Ok(read_column(manager, name)?.ticks(entity)),
+                None => Ok(None),
+            },
+            Storage::Archetype(archetypes) => archetypes.ticks(entity, type_id),
+        }
+    }
+
+    pub fn component_types(&self, entity: Entity) -> Result<Vec<TypeId>, EcsError> {
+        match self {
+            Storage::SparseSet(managers) => {
+                let mut type_ids = vec![];
+                for (type_id, (name, manager)) in managers.iter() {
+                    if read_column(manager, name)?.has(entity) {
+                        type_ids.push(*type_id);
+                    }
+                }
+                Ok(type_ids)
+            }
+            Storage::Archetype(archetypes) => Ok(archetypes.component_types(entity)),
+        }
+    }
+
+    /// Entities that may have every type in `type_ids`, some of them might not
+    pub fn candidates(&self, type_ids: &[TypeId], dst: &mut Vec<Entity>) -> Result<(), EcsError> {
+        match self {
+            Storage::SparseSet(managers) => {
+                if let Some((name, manager)) = type_ids.first().and_then(|id| managers.get(id)) {
+                    dst.extend(read_column(manager, name)?.entity_ids());
+                }
+            }
+            Storage::Archetype(archetypes) => archetypes.candidates(type_ids, dst),
+        }
+        Ok(())
+    }
+
+    pub fn remap(&mut self, remap: &Remap, len: usize) {
+        match self {
+            Storage::SparseSet(managers) => {
+                for (_, manager) in managers.values_mut() {
+                    manager.get_mut().unwrap().remap(remap, len);
+                }
+            }
+            Storage::Archetype(archetypes) => archetypes.remap(remap, len),
+        }
+    }
+
+    pub fn clear_removed(&mut self, before: u64) {
+        match self {
+            Storage::SparseSet(managers) => {
+                for (_, manager) in managers.values_mut() {
+                    manager.get_mut().unwrap().clear_removed(before);
+                }
+            }
+            Storage::Archetype(archetypes) => archetypes.clear_removed(before),
+        }
+    }
+
+    /// Fails if `T` is not registered or if any of its columns is borrowed mutably
+    pub fn check_readable<T: 'static + Component>(&self) -> Result<(), EcsError> {
+        self.check_registered::<T>()?;
+        let type_id = TypeId::of::<T>();
+        match self {
+            Storage::SparseSet(managers) => {
+                drop(read_column(&managers[&type_id].1, type_name::<T>())?);
+            }
+            Storage::Archetype(archetypes) => {
+                for column in archetypes
+                    .tables()
+                    .iter()
+                    .filter_map(|t| t.column(&type_id))
+                {
+                    drop(read_column(column, type_name::<T>())?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn borrow<T: 'static + Component>(&self) -> Result<ComponentsRef<'_, T>, EcsError> {
+        self.check_registered::<T>()?;
+        let type_id = TypeId::of::<T>();
+        match self {
+            Storage::SparseSet(managers) => Ok(ComponentsRef {
+                columns: vec![Some(read::<T>(&managers[&type_id].1)?)],
+                archetypes: None,
+            }),
+            Storage::Archetype(archetypes) => Ok(ComponentsRef {
+                columns: archetypes
+                    .tables()
+                    .iter()
+                    .map(|table| table.column(&type_id).map(read::<T>).transpose())
+                    .collect::<Result<_, _>>()?,
+                archetypes: Some(archetypes),
+            }),
+        }
+    }
+
+    pub fn borrow_mut<T: 'static + Component>(&self) -> Result<ComponentsRefMut<'_, T>, EcsError> {
+        self.check_registered::<T>()?;
+        let type_id = TypeId::of::<T>();
+        match self {
+            Storage::SparseSet(managers) => Ok(ComponentsRefMut {
+                columns: vec![Some(write::<T>(&managers[&type_id].1)?)],
+                archetypes: None,
+            }),
+            Storage::Archetype(archetypes) => Ok(ComponentsRefMut {
+                columns: archetypes
+                    .tables()
+                    .iter()
+                    .map(|table| table.column(&type_id).map(write::<T>).transpose())
+                    .collect::<Result<_, _>>()?,
+                archetypes: Some(archetypes),
+            }),
+        }
+    }
+}
+
+/// Reads a column of a type that is only known by its id, `name` is the name of that type
+fn read_column<'a>(
+    column: &'a Column,
+    name: &'static str,
+) -> Result<RwLockReadGuard<'a, Box<dyn ComponentManagerTrait>>, EcsError> {
+    match column.try_read() {
+        Ok(guard) => {
+            record_borrow(Borrow::component(guard.get_type_id(), name, false));
+            Ok(guard)
+        }
+        Err(error) => Err(EcsError::from_lock(error, name)),
+    }
+}
+
+fn read<T: 'static + Component>(column: &Column) -> Result<ManagerRef<'_, T>, EcsError> {
+    record_borrow(Borrow::component(
+        TypeId::of::<T>(),
+        type_name::<T>(),
+        false,
+    ));
+    match column.try_read() {
+        Ok(guard) if guard.as_any().is::<TagManager<T>>() => {
+            Err(EcsError::FetchedTag(type_name::<T>()))
+        }
+        Ok(guard) => Ok(ManagerRef::new(guard)),
+        Err(error) => Err(EcsError::from_lock(error, type_name::<T>())),
+    }
+}
+
+fn write<T: 'static + Component>(column: &Column) -> Result<ManagerRefMut<'_, T>, EcsError> {
+    record_borrow(Borrow::component(TypeId::of::<T>(), type_name::<T>(), true));
+    match column.try_write() {
+        Ok(guard) if guard.as_any().is::<TagManager<T>>() => {
+            Err(EcsError::FetchedTag(type_name::<T>()))
+        }
+        Ok(guard) => Ok(ManagerRefMut::new(guard)),
+        Err(error) => Err(EcsError::from_lock(error, type_name::<T>())),
+    }
+}
+
+/// Shared borrow of every component of type `T`, whichever storage holds them
+///
+/// In sparse set storage there is a single column, in archetype storage there is one column per
+/// table and `None` for the tables without a `T`.
+pub struct ComponentsRef<'a, T> {
+    columns: Vec<Option<ManagerRef<'a, T>>>,
+    archetypes: Option<&'a Archetypes>,
+}
+
+impl<'a, T: 'static + Component> ComponentsRef<'a, T> {
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        match self.archetypes {
+            None => self.columns[0].as_ref()?.borrow_component(entity),
+            Some(archetypes) => {
+                let (table, row) = archetypes.location(entity)?;
+                Some(&self.columns[table].as_ref()?.components[row])
+            }
+        }
+    }
+
+    pub fn has(&self, entity: Entity) -> bool {
+        self.get(entity).is_some()
+    }
+
+    pub fn ticks(&self, entity: Entity) -> Option<ComponentTicks> {
+        match self.archetypes {
+            None => self.columns[0].as_ref()?.ticks(entity),
+            Some(archetypes) => {
+                let (table, row) = archetypes.location(entity)?;
+                Some(self.columns[table].as_ref()?.ticks[row])
+            }
+        }
+    }
+
+    pub fn entity_ids(&self) -> Vec<Entity> {
+        self.columns
+            .iter()
+            .flatten()
+            .flat_map(|column| column.borrow_entity_ids().iter().copied())
+            .collect()
+    }
+
+    /// Number of columns, one per archetype table or a single one in sparse set storage
+    pub fn tables(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// Number of components in the column of `table`
+    pub fn rows(&self, table: usize) -> usize {
+        self.columns[table]
+            .as_ref()
+            .map_or(0, |column| column.entity_ids.len())
+    }
+
+    pub fn entity_at(&self, table: usize, row: usize) -> Entity {
+        self.columns[table].as_ref().unwrap().entity_ids[row]
+    }
+
+    /// The component of `entity`, read straight from `row` when the entity sits there
+    pub fn get_at(&self, table: usize, row: usize, entity: Entity) -> Option<&T> {
+        match &self.columns[table] {
+            Some(column) if column.entity_ids.get(row) == Some(&entity) => {
+                Some(&column.components[row])
+            }
+            _ => self.get(entity),
+        }
+    }
+
+    /// Every entity with its `T`, column by column in the order they are stored
+    pub fn iter(&self) -> ComponentsIter<'_, 'a, T> {
+        ComponentsIter {
+            columns: self.columns.iter(),
+            column: [].iter().copied().zip([].iter()),
+        }
+    }
+
+    /// Entities that lost their `T` after `since` with the tick they lost it at, oldest first
+    pub fn removed_since(&self, since: u64) -> Vec<(Entity, u64)> {
+        let mut removed = self
+            .columns
+            .iter()
+            .flatten()
+            .flat_map(|column| column.removed.iter())
+            .filter(|(_, tick)| *tick > since)
+            .collect::<Vec<_>>();
+        removed.sort_by_key(|(_, tick)| *tick);
+        removed.into_iter().copied().collect()
+    }
+}
+
+/// Iterator returned by `ComponentsRef::iter`
+///
+/// Each column is resolved once when the iterator reaches it, the entities and components in it
+/// are then read straight from their slices.
+pub struct ComponentsIter<'s, 'a, T> {
+    columns: slice::Iter<'s, Option<ManagerRef<'a, T>>>,
+    column: Zip<Copied<slice::Iter<'s, Entity>>, slice::Iter<'s, T>>,
+}
+
+impl<'s, 'a, T: 'static + Component> Iterator for ComponentsIter<'s, 'a, T> {
+    type Item = (Entity, &'s T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.column.next() {
+                return Some(item);
+            }
+            if let Some(column) = self.columns.next()? {
+                let column: &'s ComponentManager<T> = column;
+                self.column = column
+                    .entity_ids
+                    .iter()
+                    .copied()
+                    .zip(column.components.iter());
+            }
+        }
+    }
+}
+
+/// Exclusive borrow of every component of type `T`, see `ComponentsRef`
+pub struct ComponentsRefMut<'a, T> {
+    columns: Vec<Option<ManagerRefMut<'a, T>>>,
+    archetypes: Option<&'a Archetypes>,
+}
+
+impl<'a, T: 'static + Component> ComponentsRefMut<'a, T> {
+    /// See `ComponentsRef::tables`
+    pub fn tables(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// See `ComponentsRef::rows`
+    pub fn rows(&self, table: usize) -> usize {
+        self.columns[table]
+            .as_ref()
+            .map_or(0, |column| column.entity_ids.len())
+    }
+
+    pub fn entity_at(&self, table: usize, row: usize) -> Entity {
+        self.columns[table].as_ref().unwrap().entity_ids[row]
+    }
+
+    /// See `ComponentsRef::get_at`
+    pub fn get_mut_at(
+        &mut self,
+        table: usize,
+        row: usize,
+        entity: Entity,
+    ) -> Option<(&mut T, &mut ComponentTicks)> {
+        match &self.columns[table] {
+            Some(column) if column.entity_ids.get(row) == Some(&entity) => {
+                let column = &mut **self.columns[table].as_mut().unwrap();
+                Some((&mut column.components[row], &mut column.ticks[row]))
+            }
+            _ => self.get_mut(entity),
+        }
+    }
+
+    /// Every entity with its `T` and the ticks to stamp on writes, see `ComponentsRef::iter`
+    pub fn iter_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (Entity, &mut T, &mut ComponentTicks)> + use<'_, 'a, T> {
+        self.columns.iter_mut().flatten().flat_map(|column| {
+            let column = &mut **column;
+            column
+                .entity_ids
+                .iter()
+                .copied()
+                .zip(column.components.iter_mut())
+                .zip(column.ticks.iter_mut())
+                .map(|((entity, component), ticks)| (entity, component, ticks))
+        })
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<(&mut T, &mut ComponentTicks)> {
+        match self.archetypes {
+            None => self.columns[0].as_mut()?.borrow_component_mut(entity),
+            Some(archetypes) => {
+                let (table, row) = archetypes.location(entity)?;
+                let column = &mut **self.columns[table].as_mut()?;
+                Some((&mut column.components[row], &mut column.ticks[row]))
+            }
+        }
+    }
+}
@@ -1,7 +1,169 @@
-use crate::core::gui::event_handler::Action;
+use std::any::{type_name, TypeId};
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
 
-use super::{entityidaccessor::EntityIdAccessor, entitymanager::EntityManager};
+use super::{
+    command::Commands, entityidaccessor::EntityIdAccessor, entitymanager::EntityManager,
+    event::Events, Component,
+};
 
-pub trait System {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, action: &Action);
+pub trait System: Send {
+    fn update(&mut self, em: &EntityManager, eia: &mut EntityIdAccessor, commands: &mut Commands);
+
+    /// Components and resources the system reads and writes
+    ///
+    /// Systems whose accesses do not conflict may run at the same time. A system that does not
+    /// declare its access is treated as exclusive and always runs on its own. Debug builds check
+    /// every borrow the system makes against this declaration and panic on undeclared ones.
+    fn access(&self) -> SystemAccess {
+        SystemAccess::exclusive()
+    }
+
+    fn name(&self) -> &'static str {
+        type_name::<Self>()
+    }
+}
+
+/// Declaration of what a system, or a run condition, touches in the `EntityManager`
+#[derive(Debug, Default, Clone)]
+pub struct SystemAccess {
+    component_reads: Vec<TypeId>,
+    component_writes: Vec<TypeId>,
+    resource_reads: Vec<TypeId>,
+    resource_writes: Vec<TypeId>,
+    exclusive: bool,
+}
+
+impl SystemAccess {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn exclusive() -> Self {
+        Self {
+            exclusive: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn read<T: 'static + Component>(mut self) -> Self {
+        self.component_reads.push(TypeId::of::<T>());
+        self
+    }
+
+    pub fn write<T: 'static + Component>(mut self) -> Self {
+        self.component_writes.push(TypeId::of::<T>());
+        self
+    }
+
+    pub fn read_resource<T: 'static>(mut self) -> Self {
+        self.resource_reads.push(TypeId::of::<T>());
+        self
+    }
+
+    pub fn write_resource<T: 'static>(mut self) -> Self {
+        self.resource_writes.push(TypeId::of::<T>());
+        self
+    }
+
+    pub fn read_event<T: 'static>(self) -> Self {
+        self.read_resource::<Events<T>>()
+    }
+
+    pub fn write_event<T: 'static>(self) -> Self {
+        self.write_resource::<Events<T>>()
+    }
+
+    pub fn extend(&mut self, other: &SystemAccess) {
+        self.component_reads.extend(&other.component_reads);
+        self.component_writes.extend(&other.component_writes);
+        self.resource_reads.extend(&other.resource_reads);
+        self.resource_writes.extend(&other.resource_writes);
+        self.exclusive |= other.exclusive;
+    }
+
+    /// Whether `borrow` is covered by what is declared, a write also covers reading
+    #[cfg(debug_assertions)]
+    pub(crate) fn allows(&self, borrow: &Borrow) -> bool {
+        if self.exclusive {
+            return true;
+        }
+        let (reads, writes) = match borrow.resource {
+            true => (&self.resource_reads, &self.resource_writes),
+            false => (&self.component_reads, &self.component_writes),
+        };
+        writes.contains(&borrow.type_id) || (!borrow.mutable && reads.contains(&borrow.type_id))
+    }
+
+    pub fn conflicts_with(&self, other: &SystemAccess) -> bool {
+        if self.exclusive || other.exclusive {
+            return true;
+        }
+        overlaps(&self.component_writes, &other.component_writes)
+            || overlaps(&self.component_writes, &other.component_reads)
+            || overlaps(&self.component_reads, &other.component_writes)
+            || overlaps(&self.resource_writes, &other.resource_writes)
+            || overlaps(&self.resource_writes, &other.resource_reads)
+            || overlaps(&self.resource_reads, &other.resource_writes)
+    }
+}
+
+fn overlaps(a: &[TypeId], b: &[TypeId]) -> bool {
+    a.iter().any(|type_id| b.contains(type_id))
+}
+
+/// A borrow of a component or resource that a system actually made while it ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Borrow {
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+    pub resource: bool,
+    pub mutable: bool,
+}
+
+impl Borrow {
+    pub fn component(type_id: TypeId, type_name: &'static str, mutable: bool) -> Self {
+        Self {
+            type_id,
+            type_name,
+            resource: false,
+            mutable,
+        }
+    }
+
+    pub fn resource<T: 'static>(mutable: bool) -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            type_name: type_name::<T>(),
+            resource: true,
+            mutable,
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    static BORROWS: RefCell<Option<Vec<Borrow>>> = const { RefCell::new(None) };
+}
+
+/// Notes a borrow made by the system running on this thread, only debug builds keep track
+pub(crate) fn record_borrow(borrow: Borrow) {
+    #[cfg(debug_assertions)]
+    BORROWS.with(|borrows| {
+        if let Some(borrows) = borrows.borrow_mut().as_mut() {
+            borrows.push(borrow);
+        }
+    });
+    #[cfg(not(debug_assertions))]
+    let _ = borrow;
+}
+
+/// Runs `f` and returns every borrow recorded on this thread while it ran
+#[cfg(debug_assertions)]
+pub(crate) fn recording_borrows(f: impl FnOnce()) -> Vec<Borrow> {
+    BORROWS.with(|borrows| *borrows.borrow_mut() = Some(vec![]));
+    f();
+    BORROWS
+        .with(|borrows| borrows.borrow_mut().take())
+        .unwrap_or_default()
 }
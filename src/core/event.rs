@@ -0,0 +1,24 @@
+use super::ecs::entities::{Entity, Remap, RemapEntities};
+
+pub struct AppleEaten {
+    pub apple: Entity,
+    pub snek: Entity,
+}
+
+/// A snake ran its head into a wall, a tail or another snake
+pub struct SnakeDied {
+    pub snek: Entity,
+}
+
+impl RemapEntities for AppleEaten {
+    fn remap_entities(&mut self, remap: &Remap) {
+        self.apple.remap_entities(remap);
+        self.snek.remap_entities(remap);
+    }
+}
+
+impl RemapEntities for SnakeDied {
+    fn remap_entities(&mut self, remap: &Remap) {
+        self.snek.remap_entities(remap);
+    }
+}
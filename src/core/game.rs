@@ -1,98 +1,419 @@
 use std::{thread, time::Duration};
 
 use super::{
+    bundle::{AppleBundle, SnakeHeadBundle, WallBundle},
+    clock::Clock,
     component::{
-        Apple, Arena, Collidable, Debugging, Position, Render, Snek, Type, Types, Velocity,
+        Apple, Collidable, Dead, Debugging, Position, Render, Snek, Tail, TurnQueue, Velocity,
     },
-    ecs::{simulation::Simulation, system::System},
-    gui::{event_handler::Action, screen::Screen, window::Window, Pos, Size},
-    system::{
-        AppleSpawningSystem, CollisionCheckSystem, DeathSystem, DebugSystem, MoveSystem,
-        RenderSystem, VelocitySystem, WrappingBoundrySystem,
+    ecs::{error::EcsError, simulation::Simulation},
+    gui::{
+        event_handler::{Action, EventHandler},
+        input::{Input, InputSource},
+        screen::Screen,
+        window::Window,
+        Pos, Size,
     },
+    plugin::{GameplayPlugin, RenderPlugin},
+    resource::{Arena, GameConfig, GameRng, GameState, Score, Time},
 };
 
+/// Simulation steps per second the game starts out with
+const TICK_RATE: u32 = 15;
+
+/// Steps run back to back at most when the game falls behind
+const MAX_STEPS_PER_FRAME: u32 = 5;
+
 pub struct Game {
     simulation: Simulation,
     is_running: bool,
 }
 
 impl Game {
-    pub fn new(screen: Screen, arena_height: i16, arena_width: i16) -> Self {
+    /// Sets up a game on an arena of the given size, `seed` picks the game to play and a random
+    /// one is picked when it is `None`
+    pub fn new(
+        screen: Screen,
+        arena_height: i16,
+        arena_width: i16,
+        seed: Option<u64>,
+    ) -> Result<Self, EcsError> {
         let window = Window::new(Pos::new(10, 1), Size::new(140, 40));
+        let mut simulation = Self::setup(arena_height, arena_width, seed)?;
+        simulation.set_input(EventHandler::new());
+        simulation.add_plugin(RenderPlugin { window, screen });
+
+        Ok(Self {
+            simulation,
+            is_running: false,
+        })
+    }
+
+    /// Sets up a game that is not drawn and takes its actions from `input`, it does not need a
+    /// terminal so it can run in tests
+    pub fn headless<I: 'static + InputSource>(
+        arena_height: i16,
+        arena_width: i16,
+        seed: u64,
+        input: I,
+    ) -> Result<Self, EcsError> {
+        let mut simulation = Self::setup(arena_height, arena_width, Some(seed))?;
+        simulation.set_input(input);
+
+        Ok(Self {
+            simulation,
+            is_running: false,
+        })
+    }
+
+    fn setup(
+        arena_height: i16,
+        arena_width: i16,
+        seed: Option<u64>,
+    ) -> Result<Simulation, EcsError> {
+        let seed = seed.unwrap_or_else(rand::random);
         let mut simulation = Simulation::new();
         simulation.register_component::<Position>();
         simulation.register_component::<Velocity>();
+        simulation.register_component::<TurnQueue>();
+        simulation.register_component::<Tail>();
+        simulation.remap_component::<Tail>();
         simulation.register_component::<Render>();
-        simulation.register_component::<Collidable>();
-        simulation.register_component::<Arena>();
-        simulation.register_component::<Snek>();
-        simulation.register_component::<Apple>();
         simulation.register_component::<Debugging>();
-        simulation.register_component::<Type>();
-
-        let entity_id = simulation.create_entity();
-        simulation.add_component_to_entity(entity_id, Snek { is_alive: true });
-        simulation.add_component_to_entity(entity_id, Type { typ: Types::Snek });
-        simulation.add_component_to_entity(entity_id, Position { x: 7, y: 7 });
-        simulation.add_component_to_entity(entity_id, Velocity { x: 1, y: 0 });
-        simulation.add_component_to_entity(entity_id, Render { sprite: '🟢' });
-        simulation.add_component_to_entity(entity_id, Collidable { collided: false });
-        simulation.add_component_to_entity(entity_id, Debugging::default());
-
-        let entity_id = simulation.create_entity();
-        simulation.add_component_to_entity(entity_id, Apple { is_alive: true });
-        simulation.add_component_to_entity(entity_id, Type { typ: Types::Apple });
-        simulation.add_component_to_entity(entity_id, Position { x: 5, y: 5 });
-        simulation.add_component_to_entity(entity_id, Render { sprite: '🍎' });
-        simulation.add_component_to_entity(entity_id, Collidable { collided: false });
-        simulation.add_component_to_entity(entity_id, Debugging::default());
-
-        let entity_id = simulation.create_entity();
-        simulation.add_component_to_entity(
-            entity_id,
-            Arena {
-                width: arena_width,
-                height: arena_height,
-            },
-        );
-
-        for x in 0..arena_height {
-            for y in 0..arena_width {
-                if x == 0 || x == arena_height - 1 || (y == 0 || y == arena_width - 1) {
-                    let entity_id = simulation.create_entity();
-                    simulation.add_component_to_entity(entity_id, Position { x, y });
-                    simulation.add_component_to_entity(entity_id, Render { sprite: '▩' });
-                    simulation.add_component_to_entity(entity_id, Collidable { collided: false });
+        simulation.register_tag::<Collidable>();
+        simulation.register_tag::<Snek>();
+        simulation.register_tag::<Apple>();
+        simulation.register_tag::<Dead>();
+
+        simulation.spawn(SnakeHeadBundle {
+            position: Position { x: 7, y: 7 },
+            velocity: Velocity { x: 1, y: 0 },
+        })?;
+        simulation.spawn(AppleBundle {
+            position: Position { x: 5, y: 5 },
+        })?;
+
+        simulation.insert_resource(Arena {
+            width: arena_width,
+            height: arena_height,
+        });
+        simulation.insert_resource(Score::new(seed));
+        simulation.insert_resource(GameRng::new(seed));
+        simulation.insert_resource(GameState::Playing);
+        simulation.insert_resource(GameConfig::default());
+        simulation.insert_resource(Time::new(TICK_RATE));
+
+        for x in 0..arena_width {
+            for y in 0..arena_height {
+                if x == 0 || x == arena_width - 1 || (y == 0 || y == arena_height - 1) {
+                    simulation.spawn(WallBundle {
+                        position: Position { x, y },
+                    })?;
                 }
             }
         }
 
-        simulation.add_system(VelocitySystem {});
-        simulation.add_system(MoveSystem {});
-        simulation.add_system(CollisionCheckSystem {});
-        simulation.add_system(WrappingBoundrySystem {});
-        simulation.add_system(DeathSystem {});
-        simulation.add_system(AppleSpawningSystem {});
-        simulation.add_system(DebugSystem {});
-        simulation.add_system(RenderSystem::new(window, screen));
+        simulation.add_plugin(GameplayPlugin);
 
-        Self {
-            simulation,
-            is_running: false,
-        }
+        Ok(simulation)
     }
 
     pub fn run(&mut self) {
         self.is_running = true;
 
-        let duration = Duration::from_millis(1000 / 15);
+        let mut clock = Clock::new(MAX_STEPS_PER_FRAME);
         while self.is_running {
-            thread::sleep(duration);
-            let action = self.simulation.update();
-            if action == Action::Exit {
-                break;
+            for _ in 0..clock.steps(self.delta()) {
+                if !self.step() {
+                    self.is_running = false;
+                    break;
+                }
             }
+            thread::sleep(clock.until_next_step(self.delta()));
+        }
+    }
+
+    /// Replaces the rules the game is played with, meant to be called before it starts
+    pub fn set_config(&mut self, config: GameConfig) -> &mut Self {
+        self.simulation.insert_resource(config);
+        self
+    }
+
+    /// Advances the game by one fixed step, returns `false` once the player exits
+    pub fn step(&mut self) -> bool {
+        if let Ok(mut time) = self.simulation.resource_mut::<Time>() {
+            time.advance();
+        }
+        self.simulation.update();
+        !self
+            .simulation
+            .resource::<Input>()
+            .is_ok_and(|input| input.pressed(&Action::Exit))
+    }
+
+    pub fn simulation(&self) -> &Simulation {
+        &self.simulation
+    }
+
+    fn delta(&self) -> Duration {
+        match self.simulation.resource::<Time>() {
+            Ok(time) => time.delta,
+            Err(_) => Time::new(TICK_RATE).delta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{
+        component::Snek,
+        ecs::{
+            entities::{Compaction, Entity},
+            entityidaccessor::EntityIdAccessor,
+            filter::Without,
+        },
+        gui::input::ScriptedInput,
+    };
+
+    use super::*;
+
+    fn headless(script: Vec<(u64, Action)>) -> Game {
+        Game::headless(20, 20, 7, ScriptedInput::new(script)).unwrap()
+    }
+
+    /// Steps the game until it exits, returns the number of steps it took
+    fn play(game: &mut Game) -> u32 {
+        let mut steps = 1;
+        while game.step() {
+            steps += 1;
+        }
+        steps
+    }
+
+    fn snek(game: &Game) -> Entity {
+        let em = &game.simulation().entity_manager;
+        EntityIdAccessor::new().borrow_ids::<Snek>(em).unwrap()[0]
+    }
+
+    fn position_of(game: &Game, entity: Entity) -> (i16, i16) {
+        let positions = game
+            .simulation()
+            .entity_manager
+            .query::<&Position>()
+            .unwrap();
+        let position = positions.get(entity).unwrap();
+        (position.x, position.y)
+    }
+
+    #[test]
+    pub fn should_play_headless_until_exit() {
+        let mut game = headless(vec![(0, Action::Down), (3, Action::Exit)]);
+
+        assert_eq!(play(&mut game), 4);
+        assert_eq!(position_of(&game, snek(&game)), (7, 11));
+    }
+
+    #[test]
+    pub fn should_not_reverse_into_itself() {
+        let mut game = headless(vec![(0, Action::Left), (2, Action::Exit)]);
+        play(&mut game);
+
+        assert_eq!(position_of(&game, snek(&game)), (10, 7));
+    }
+
+    #[test]
+    pub fn should_take_quick_turns_one_per_move() {
+        let mut game = headless(vec![
+            (0, Action::Up),
+            (0, Action::Left),
+            (0, Action::Down),
+            (2, Action::Exit),
+        ]);
+        play(&mut game);
+
+        assert_eq!(position_of(&game, snek(&game)), (6, 7));
+    }
+
+    fn tail(game: &Game) -> Vec<Entity> {
+        game.simulation()
+            .entity_manager
+            .query::<&Tail>()
+            .unwrap()
+            .get(snek(game))
+            .unwrap()
+            .segments
+            .clone()
+    }
+
+    #[test]
+    pub fn should_grow_tail_behind_head_when_eating_apple() {
+        // The first apple is at (5, 5) and gets eaten on step 3
+        let mut game = headless(vec![
+            (0, Action::Up),
+            (0, Action::Left),
+            (3, Action::Up),
+            (6, Action::Exit),
+        ]);
+        game.set_config(GameConfig { growth: 2 });
+        play(&mut game);
+
+        let positions = tail(&game)
+            .iter()
+            .map(|segment| position_of(&game, *segment))
+            .collect::<Vec<_>>();
+        assert_eq!(position_of(&game, snek(&game)), (5, 2));
+        assert_eq!(positions, [(5, 3), (5, 4)]);
+    }
+
+    #[test]
+    pub fn should_keep_tail_following_head_when_compacting() {
+        // The tail is done growing by step 6, then the corner wall makes room for a segment to
+        // move into
+        let mut game = headless(vec![
+            (0, Action::Up),
+            (0, Action::Left),
+            (3, Action::Up),
+            (6, Action::Right),
+            (9, Action::Exit),
+        ]);
+        game.set_config(GameConfig { growth: 2 });
+        game.simulation.compact_every(1, Compaction { min_idle: 0 });
+        for _ in 0..6 {
+            game.step();
+        }
+        let segments = tail(&game);
+        let corner = game
+            .simulation()
+            .entity_manager
+            .query::<&Position>()
+            .unwrap()
+            .iter()
+            .find(|(_, position)| (position.x, position.y) == (0, 0))
+            .map(|(entity, _)| entity)
+            .unwrap();
+        game.simulation.remove_entity(corner).unwrap();
+
+        let mut moved = vec![];
+        let mut running = true;
+        while running {
+            running = game.step();
+            moved.extend_from_slice(game.simulation().last_remap().moved());
+        }
+        assert!(moved.iter().any(|(old, _)| segments.contains(old)));
+
+        let positions = tail(&game)
+            .iter()
+            .map(|segment| position_of(&game, *segment))
+            .collect::<Vec<_>>();
+        assert_eq!(position_of(&game, snek(&game)), (9, 3));
+        assert_eq!(positions, [(8, 3), (7, 3)]);
+    }
+
+    fn is_over(game: &Game) -> bool {
+        let snek = snek(game);
+        let state = *game.simulation().resource::<GameState>().unwrap();
+        state == GameState::GameOver && game.simulation().has_component::<Dead>(snek).unwrap()
+    }
+
+    #[test]
+    pub fn should_die_on_wall() {
+        // The wall on the right is at x 19, 12 moves away
+        let mut game = headless(vec![(20, Action::Exit)]);
+        play(&mut game);
+
+        assert!(is_over(&game));
+        assert_eq!(position_of(&game, snek(&game)), (19, 7));
+    }
+
+    #[test]
+    pub fn should_die_on_own_tail() {
+        // Eats the apple at (5, 5) on step 3 and then circles back into its tail
+        let mut game = headless(vec![
+            (0, Action::Up),
+            (0, Action::Left),
+            (3, Action::Up),
+            (4, Action::Left),
+            (5, Action::Down),
+            (6, Action::Right),
+            (7, Action::Up),
+            (12, Action::Exit),
+        ]);
+        game.set_config(GameConfig { growth: 4 });
+        play(&mut game);
+
+        assert!(is_over(&game));
+        assert_eq!(position_of(&game, snek(&game)), (5, 5));
+    }
+
+    #[test]
+    pub fn should_wall_in_non_square_arena() {
+        // The arena is 30 wide and 12 high, the wall on the right is at x 29, 22 moves away
+        let mut game =
+            Game::headless(12, 30, 7, ScriptedInput::new(vec![(30, Action::Exit)])).unwrap();
+
+        let em = &game.simulation().entity_manager;
+        let walls = EntityIdAccessor::new()
+            .borrow_ids_filtered::<Collidable, (Without<Snek>, Without<Apple>)>(em)
+            .unwrap()
+            .clone();
+        let positions = walls
+            .iter()
+            .map(|wall| position_of(&game, *wall))
+            .collect::<Vec<_>>();
+        assert_eq!(positions.len(), 2 * 30 + 2 * 10);
+        assert!(positions
+            .iter()
+            .all(|(x, y)| *x == 0 || *x == 29 || *y == 0 || *y == 11));
+        assert!(positions
+            .iter()
+            .all(|(x, y)| (0..30).contains(x) && (0..12).contains(y)));
+
+        for _ in 0..15 {
+            game.step();
+        }
+        assert!(!is_over(&game));
+        play(&mut game);
+        assert!(is_over(&game));
+        assert_eq!(position_of(&game, snek(&game)), (29, 7));
+    }
+
+    /// Every entity with its position, plus the score and the state of the game
+    fn snapshot(game: &Game) -> (Vec<(Entity, i16, i16)>, u32, GameState) {
+        let em = &game.simulation().entity_manager;
+        let mut positions = em
+            .query::<&Position>()
+            .unwrap()
+            .iter()
+            .map(|(entity, position)| (entity, position.x, position.y))
+            .collect::<Vec<_>>();
+        positions.sort_by_key(|(entity, ..)| entity.index());
+        let apples = game.simulation().resource::<Score>().unwrap().apples;
+        let state = *game.simulation().resource::<GameState>().unwrap();
+        (positions, apples, state)
+    }
+
+    #[test]
+    pub fn should_play_the_same_in_parallel_and_serially() {
+        let script = || {
+            vec![
+                (0, Action::Up),
+                (0, Action::Left),
+                (3, Action::Up),
+                (4, Action::Left),
+                (5, Action::Down),
+                (6, Action::Right),
+                (10, Action::Exit),
+            ]
+        };
+        let mut parallel = headless(script());
+        let mut serial = headless(script());
+        serial.simulation.set_parallel(false);
+
+        while parallel.step() {
+            assert!(serial.step());
+            assert_eq!(snapshot(&parallel), snapshot(&serial));
         }
+        assert!(!serial.step());
+        assert_eq!(snapshot(&parallel), snapshot(&serial));
     }
 }
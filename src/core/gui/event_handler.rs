@@ -19,8 +19,8 @@ pub enum Action {
     Down,
     Left,
     Right,
+    Pause,
     Exit,
-    None,
 }
 
 impl EventHandler {
@@ -82,6 +82,10 @@ impl EventWorker {
                             code: KeyCode::Char('d'),
                             modifiers: KeyModifiers::NONE,
                         }) => sender.send(Action::Right).unwrap_or(()),
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Char('p'),
+                            modifiers: KeyModifiers::NONE,
+                        }) => sender.send(Action::Pause).unwrap_or(()),
                         _ => (),
                     };
                 }
@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+
+use super::event_handler::{Action, EventHandler};
+
+/// Where a `Simulation` gets the actions of the player from
+///
+/// The terminal is read through `EventHandler`, while `ScriptedInput` and `ChannelInput` feed
+/// actions without a terminal so the game can run headless, in tests for example.
+pub trait InputSource {
+    /// Next action pending at `tick`, `None` once there is nothing left for this tick
+    fn poll(&mut self, tick: u64) -> Option<Action>;
+}
+
+impl InputSource for EventHandler {
+    fn poll(&mut self, _tick: u64) -> Option<Action> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// Resource with every action the player made since the previous update, in order
+///
+/// `Simulation` drains its `InputSource` into it at the start of each update, so presses that
+/// came in together are all seen in the same update instead of trickling in one per update.
+#[derive(Debug, Default)]
+pub struct Input {
+    actions: Vec<Action>,
+}
+
+impl Input {
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    pub fn pressed(&self, action: &Action) -> bool {
+        self.actions.contains(action)
+    }
+
+    /// Replaces the actions with everything `source` has pending at `tick`
+    pub(crate) fn drain(&mut self, source: &mut dyn InputSource, tick: u64) {
+        self.actions.clear();
+        while let Some(action) = source.poll(tick) {
+            self.actions.push(action);
+        }
+    }
+}
+
+/// Plays back a fixed list of `(tick, action)` pairs
+///
+/// Actions are handed out at the first poll on or after their tick, in the order they were listed
+/// for that tick.
+#[derive(Debug, Default)]
+pub struct ScriptedInput {
+    script: VecDeque<(u64, Action)>,
+}
+
+impl ScriptedInput {
+    pub fn new(mut script: Vec<(u64, Action)>) -> Self {
+        script.sort_by_key(|(tick, _)| *tick);
+        Self {
+            script: script.into(),
+        }
+    }
+
+    /// Tells if every action of the script has been handed out
+    pub fn is_done(&self) -> bool {
+        self.script.is_empty()
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn poll(&mut self, tick: u64) -> Option<Action> {
+        match self.script.front() {
+            Some((at, _)) if *at <= tick => self.script.pop_front().map(|(_, action)| action),
+            _ => None,
+        }
+    }
+}
+
+/// Hands out whatever actions were sent through its `Sender`, like input from another thread
+pub struct ChannelInput {
+    receiver: Receiver<Action>,
+}
+
+impl ChannelInput {
+    pub fn new() -> (Sender<Action>, Self) {
+        let (sender, receiver) = unbounded();
+        (sender, Self { receiver })
+    }
+}
+
+impl InputSource for ChannelInput {
+    fn poll(&mut self, _tick: u64) -> Option<Action> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn should_hand_out_scripted_actions_at_their_tick() {
+        let mut input =
+            ScriptedInput::new(vec![(2, Action::Left), (0, Action::Up), (2, Action::Down)]);
+
+        assert_eq!(input.poll(0), Some(Action::Up));
+        assert_eq!(input.poll(0), None);
+        assert_eq!(input.poll(1), None);
+        assert_eq!(input.poll(3), Some(Action::Left));
+        assert_eq!(input.poll(3), Some(Action::Down));
+        assert!(input.is_done());
+    }
+
+    #[test]
+    pub fn should_drain_every_pending_action() {
+        let mut source =
+            ScriptedInput::new(vec![(0, Action::Up), (0, Action::Left), (1, Action::Exit)]);
+        let mut input = Input::default();
+
+        input.drain(&mut source, 0);
+        assert_eq!(input.actions(), [Action::Up, Action::Left]);
+        input.drain(&mut source, 1);
+        assert_eq!(input.actions(), [Action::Exit]);
+        assert!(input.pressed(&Action::Exit));
+    }
+
+    #[test]
+    pub fn should_hand_out_sent_actions() {
+        let (sender, mut input) = ChannelInput::new();
+        sender.send(Action::Pause).unwrap();
+
+        assert_eq!(input.poll(0), Some(Action::Pause));
+        assert_eq!(input.poll(0), None);
+    }
+}
@@ -1,5 +1,3 @@
-use std::io::Write;
-
 use unicode_width::UnicodeWidthChar;
 
 use super::{
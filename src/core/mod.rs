@@ -2,5 +2,10 @@ pub mod ecs;
 pub mod game;
 pub mod gui;
 
+mod bundle;
+mod clock;
 mod component;
+mod event;
+mod plugin;
+mod resource;
 mod system;
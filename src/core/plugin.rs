@@ -0,0 +1,81 @@
+use super::{
+    ecs::{
+        plugin::Plugin,
+        schedule::{resource_equals, Stage, SystemConfig},
+        simulation::Simulation,
+    },
+    event::{AppleEaten, SnakeDied},
+    gui::{screen::Screen, window::Window},
+    resource::GameState,
+    system::{
+        AppleSpawningSystem, CollisionCheckSystem, DeathSystem, DebugSystem, MoveSystem,
+        PauseSystem, RenderSystem, VelocitySystem,
+    },
+};
+
+/// Systems that make up the rules of the game, they only run while the game is being played
+pub struct GameplayPlugin;
+
+impl Plugin for GameplayPlugin {
+    fn build(self, simulation: &mut Simulation) {
+        simulation.add_event::<AppleEaten>();
+        simulation.add_event::<SnakeDied>();
+        simulation.remap_event::<AppleEaten>();
+        simulation.remap_event::<SnakeDied>();
+
+        simulation.add_system_config(
+            SystemConfig::new(PauseSystem {})
+                .stage(Stage::Input)
+                .label("pause"),
+        );
+        simulation.add_system_config(
+            SystemConfig::new(VelocitySystem {})
+                .stage(Stage::Input)
+                .after("pause")
+                .run_if(resource_equals(GameState::Playing)),
+        );
+        simulation.add_system_config(
+            SystemConfig::new(MoveSystem {})
+                .label("move")
+                .run_if(resource_equals(GameState::Playing)),
+        );
+        simulation.add_system_config(
+            SystemConfig::new(CollisionCheckSystem {})
+                .stage(Stage::Collision)
+                .label("collision")
+                .run_if(resource_equals(GameState::Playing)),
+        );
+        simulation.add_system_config(
+            SystemConfig::new(DeathSystem::new())
+                .stage(Stage::Collision)
+                .after("collision")
+                .run_if(resource_equals(GameState::Playing)),
+        );
+        simulation.add_system_config(
+            SystemConfig::new(AppleSpawningSystem {})
+                .stage(Stage::Cleanup)
+                .run_if(resource_equals(GameState::Playing)),
+        );
+    }
+}
+
+/// Draws the game and its debug information to the terminal
+pub struct RenderPlugin {
+    pub window: Window,
+    pub screen: Screen,
+}
+
+impl Plugin for RenderPlugin {
+    fn build(self, simulation: &mut Simulation) {
+        simulation.add_system_config(
+            SystemConfig::new(DebugSystem {})
+                .stage(Stage::Render)
+                .label("debug"),
+        );
+        simulation.add_system_config(
+            SystemConfig::new(RenderSystem::new(self.window, self.screen))
+                .stage(Stage::Render)
+                .after("debug"),
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+pub struct Arena {
+    pub width: i16,
+    pub height: i16,
+}
+
+/// Score of the current game, along with the seed it was played with so it can be replayed
+pub struct Score {
+    pub apples: u32,
+    pub seed: u64,
+}
+
+impl Score {
+    pub fn new(seed: u64) -> Self {
+        Self { apples: 0, seed }
+    }
+}
+
+/// The one source of randomness of the game, systems must not use any other
+///
+/// Two games started with the same seed play out the same as long as the player does the same.
+/// The seed itself is kept in `Score`.
+pub struct GameRng {
+    rng: StdRng,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// Rules of the game that can be tuned before it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameConfig {
+    /// Segments the tail grows by for every apple eaten
+    pub growth: u32,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self { growth: 1 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameState {
+    Playing,
+    Paused,
+    GameOver,
+}
+
+/// Simulation clock, advanced by `Game` right before every step
+///
+/// Steps are a fixed `delta` apart in simulated time. Systems can change the pace of the game
+/// with `set_tick_rate`, which takes effect from the next step on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Time {
+    /// Time between two steps
+    pub delta: Duration,
+    /// Simulated time since the game started
+    pub elapsed: Duration,
+    /// Number of steps run since the game started
+    pub tick: u64,
+    tick_rate: u32,
+}
+
+impl Time {
+    /// A clock running `tick_rate` steps per second
+    pub fn new(tick_rate: u32) -> Self {
+        let mut time = Self {
+            delta: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            tick: 0,
+            tick_rate: 0,
+        };
+        time.set_tick_rate(tick_rate);
+        time
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.tick_rate = tick_rate.max(1);
+        self.delta = Duration::from_secs(1) / self.tick_rate;
+    }
+
+    pub fn advance(&mut self) {
+        self.elapsed += self.delta;
+        self.tick += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    pub fn should_repeat_sequence_for_same_seed() {
+        let mut first = GameRng::new(42);
+        let mut second = GameRng::new(42);
+        let draw = |rng: &mut GameRng| (0..8).map(|_| rng.gen_range(0..100)).collect::<Vec<_>>();
+
+        assert_eq!(draw(&mut first), draw(&mut second));
+    }
+}
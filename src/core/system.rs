@@ -1,18 +1,40 @@
 use rand::Rng;
 
 use super::{
+    bundle::{AppleBundle, SegmentBundle},
     component::{
-        Apple, Arena, Collidable, Debugging, Position, Render, Snek, Type, Types, Velocity,
+        Apple, Collidable, Dead, Debugging, Position, Render, Snek, Tail, TurnQueue, Velocity,
     },
-    ecs::{entityidaccessor::EntityIdAccessor, entitymanager::EntityManager, system::System},
-    gui::{buffer::Style, event_handler::Action, screen::Screen, window::Window, Pos, Size},
+    ecs::{
+        command::Commands,
+        entities::Entity,
+        entityidaccessor::EntityIdAccessor,
+        entitymanager::EntityManager,
+        error::EcsError,
+        event::EventReader,
+        filter::{Changed, With},
+        query::Query,
+        system::{System, SystemAccess},
+    },
+    event::{AppleEaten, SnakeDied},
+    gui::{
+        buffer::Style, event_handler::Action, input::Input, screen::Screen, window::Window, Pos,
+        Size,
+    },
+    resource::{Arena, GameConfig, GameRng, GameState, Score, Time},
 };
 
+/// The game speeds up by one step per second for every apple eaten, up to this rate
+const MAX_TICK_RATE: u32 = 30;
+
 pub struct MoveSystem;
 pub struct CollisionCheckSystem;
-pub struct WrappingBoundrySystem;
 pub struct VelocitySystem;
-pub struct DeathSystem;
+pub struct PauseSystem;
+pub struct DeathSystem {
+    apple_eaten: EventReader<AppleEaten>,
+    snake_died: EventReader<SnakeDied>,
+}
 pub struct AppleSpawningSystem;
 pub struct DebugSystem;
 pub struct RenderSystem {
@@ -20,176 +42,365 @@ pub struct RenderSystem {
     screen: Screen,
 }
 
-impl System for WrappingBoundrySystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, _action: &Action) {
-        let (arena_width, arena_height) = {
-            let arena = &em.borrow_components::<Arena>().unwrap()[0];
-            (arena.width, arena.height)
+impl System for CollisionCheckSystem {
+    fn update(&mut self, em: &EntityManager, eia: &mut EntityIdAccessor, _commands: &mut Commands) {
+        let snek_ids = match eia.borrow_ids_for_pair::<Position, Snek>(em) {
+            Ok(ids) => ids.clone(),
+            Err(_) => return,
         };
-        let snek_id = eia.borrow_ids_for_pair::<Velocity, Position>(em).unwrap()[0];
-        let mut snek = em.borrow_component_mut::<Position>(snek_id).unwrap();
-        if snek.x == arena_height {
-            snek.x = 0;
-        }
-
-        if snek.x < 0 {
-            snek.x = arena_height;
-        }
-
-        if snek.y == arena_width {
-            snek.y = 0;
-        }
-
-        if snek.y < 0 {
-            snek.y = arena_height;
+        let collidable_ids = match eia.borrow_ids_for_pair::<Position, Collidable>(em) {
+            Ok(ids) => ids,
+            Err(_) => return,
+        };
+        let positions = match em.query::<&Position>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let (mut apple_eaten, mut snake_died) = match (
+            em.event_writer::<AppleEaten>(),
+            em.event_writer::<SnakeDied>(),
+        ) {
+            (Ok(apple_eaten), Ok(snake_died)) => (apple_eaten, snake_died),
+            _ => return,
+        };
+        for snek_id in snek_ids {
+            // Walls, tail segments and other snakes are all collidable, only apples are harmless
+            for other_id in collidable_ids.iter().filter(|id| **id != snek_id) {
+                if !CollisionCheckSystem::check_collision(self, &positions, snek_id, *other_id) {
+                    continue;
+                }
+                if em.has_component::<Apple>(*other_id) == Ok(true) {
+                    apple_eaten.send(AppleEaten {
+                        apple: *other_id,
+                        snek: snek_id,
+                    });
+                } else {
+                    snake_died.send(SnakeDied { snek: snek_id });
+                }
+            }
         }
     }
-}
 
-impl System for CollisionCheckSystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, _action: &Action) {
-        let user_id = eia.borrow_ids_for_pair::<Position, Snek>(em).unwrap()[0];
-        let apple_ids = eia.borrow_ids_for_pair::<Position, Apple>(em).unwrap();
-        for apple_id in apple_ids.iter() {
-            if CollisionCheckSystem::check_collision(self, em, user_id, *apple_id) {
-                let (c, _) = em
-                    .borrow_component_pair_mut::<Collidable, Apple>(*apple_id)
-                    .unwrap();
-                c.collided = true;
-            }
-        }
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .read::<Position>()
+            .read::<Snek>()
+            .read::<Apple>()
+            .read::<Collidable>()
+            .write_event::<AppleEaten>()
+            .write_event::<SnakeDied>()
     }
 }
 
 impl CollisionCheckSystem {
     fn check_collision(
         &self,
-        manager: &EntityManager,
-        entity_id1: usize,
-        entity_id2: usize,
+        positions: &Query<&Position>,
+        entity1: Entity,
+        entity2: Entity,
     ) -> bool {
-        let position1 = manager.borrow_component::<Position>(entity_id1).unwrap();
-        let position2 = manager.borrow_component::<Position>(entity_id2).unwrap();
+        let position1 = positions.get(entity1).unwrap();
+        let position2 = positions.get(entity2).unwrap();
         position1.x == position2.x && position1.y == position2.y
     }
 }
 
 impl System for MoveSystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, _action: &Action) {
-        let entity_ids = eia.borrow_ids_for_pair::<Velocity, Position>(em).unwrap();
-        for id in entity_ids.iter() {
-            let (velocity, mut position) = em
-                .borrow_component_pair_mut::<Velocity, Position>(*id)
-                .unwrap();
-            position.x += velocity.x;
-            position.y += velocity.y;
+    fn update(&mut self, em: &EntityManager, _eia: &mut EntityIdAccessor, commands: &mut Commands) {
+        let velocities = match em.query::<&Velocity>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let mut tails = match em.query::<&mut Tail>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let mut positions = match em.query::<&mut Position>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        for (entity, velocity) in velocities.iter() {
+            let mut vacated = match positions.get_mut(entity) {
+                Some(mut position) => {
+                    let vacated = (position.x, position.y);
+                    position.x += velocity.x;
+                    position.y += velocity.y;
+                    vacated
+                }
+                None => continue,
+            };
+            let mut tail = match tails.get_mut(entity) {
+                Some(tail) => tail,
+                None => continue,
+            };
+            for segment in tail.segments.iter() {
+                if let Some(mut position) = positions.get_mut(*segment) {
+                    let (x, y) = vacated;
+                    vacated = (position.x, position.y);
+                    position.x = x;
+                    position.y = y;
+                }
+            }
+            if tail.pending > 0 {
+                tail.pending -= 1;
+                commands.add(move |em| MoveSystem::grow(em, entity, vacated));
+            }
         }
     }
+
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .read::<Velocity>()
+            .write::<Position>()
+            .write::<Tail>()
+    }
 }
 
-impl System for VelocitySystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, action: &Action) {
-        let id = eia.borrow_ids_for_pair::<Velocity, Position>(em).unwrap()[0];
-        let (velocity, _) = em
-            .borrow_component_pair_mut::<Velocity, Position>(id)
-            .unwrap();
-        match action {
-            Action::Up => {
-                velocity.x = 0;
-                velocity.y = -1;
+impl MoveSystem {
+    /// Adds a segment at the tip of the tail of `snek`, on the spot the tip just left
+    fn grow(em: &mut EntityManager, snek: Entity, (x, y): (i16, i16)) -> Result<(), EcsError> {
+        let segment = em.spawn(SegmentBundle {
+            position: Position { x, y },
+        })?;
+        let grown = match em.query::<&mut Tail>()?.get_mut(snek) {
+            Some(mut tail) => {
+                tail.segments.push(segment);
+                true
             }
-            Action::Down => {
-                velocity.x = 0;
-                velocity.y = 1;
-            }
-            Action::Left => {
-                velocity.x = -1;
-                velocity.y = 0;
+            None => false,
+        };
+        if !grown {
+            em.remove_entity(segment)?;
+        }
+        Ok(())
+    }
+}
+
+impl System for VelocitySystem {
+    fn update(
+        &mut self,
+        em: &EntityManager,
+        _eia: &mut EntityIdAccessor,
+        _commands: &mut Commands,
+    ) {
+        let input = match em.resource::<Input>() {
+            Ok(input) => input,
+            Err(_) => return,
+        };
+        let mut query = match em.query::<(&mut Velocity, &mut TurnQueue)>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        query.for_each_mut(|_, (mut velocity, mut turns)| {
+            let heading = (velocity.x, velocity.y);
+            for action in input.actions() {
+                let turn = match action {
+                    Action::Up => (0, -1),
+                    Action::Down => (0, 1),
+                    Action::Left => (-1, 0),
+                    Action::Right => (1, 0),
+                    _ => continue,
+                };
+                turns.push(heading, turn);
             }
-            Action::Right => {
-                velocity.x = 1;
-                velocity.y = 0;
+            if let Some((x, y)) = turns.pop() {
+                velocity.x = x;
+                velocity.y = y;
             }
-            _ => (),
+        });
+    }
+
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .write::<Velocity>()
+            .write::<TurnQueue>()
+            .read_resource::<Input>()
+    }
+}
+
+impl DeathSystem {
+    pub fn new() -> Self {
+        Self {
+            apple_eaten: EventReader::new(),
+            snake_died: EventReader::new(),
         }
     }
 }
 
+impl System for PauseSystem {
+    fn update(
+        &mut self,
+        em: &EntityManager,
+        _eia: &mut EntityIdAccessor,
+        _commands: &mut Commands,
+    ) {
+        let input = match em.resource::<Input>() {
+            Ok(input) => input,
+            Err(_) => return,
+        };
+        let mut state = match em.resource_mut::<GameState>() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        for _ in input
+            .actions()
+            .iter()
+            .filter(|action| **action == Action::Pause)
+        {
+            *state = match *state {
+                GameState::Playing => GameState::Paused,
+                GameState::Paused => GameState::Playing,
+                GameState::GameOver => GameState::GameOver,
+            };
+        }
+    }
+
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .write_resource::<GameState>()
+            .read_resource::<Input>()
+    }
+}
+
 impl System for DeathSystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, _action: &Action) {
-        let ids = eia.borrow_ids::<Collidable>(em).unwrap();
-        for id in ids.iter() {
-            let component = em.borrow_component::<Collidable>(*id).unwrap();
-            if component.collided {
-                let (apple, collidable) = em
-                    .borrow_component_pair_mut::<Apple, Collidable>(*id)
-                    .unwrap();
-                collidable.collided = false;
-                apple.is_alive = false;
+    fn update(&mut self, em: &EntityManager, _eia: &mut EntityIdAccessor, commands: &mut Commands) {
+        let (apple_eaten, snake_died) = match (em.events::<AppleEaten>(), em.events::<SnakeDied>())
+        {
+            (Ok(apple_eaten), Ok(snake_died)) => (apple_eaten, snake_died),
+            _ => return,
+        };
+        let mut died = vec![];
+        for event in self.snake_died.read(&snake_died) {
+            if died.contains(&event.snek) || em.has_component::<Dead>(event.snek) != Ok(false) {
+                continue;
+            }
+            died.push(event.snek);
+            commands.insert(event.snek, Dead);
+        }
+        if !died.is_empty() {
+            if let Ok(mut state) = em.resource_mut::<GameState>() {
+                *state = GameState::GameOver;
+            }
+        }
+
+        let mut score = em.resource_mut::<Score>();
+        let mut time = em.resource_mut::<Time>();
+        let growth = em
+            .resource::<GameConfig>()
+            .map_or(0, |config| config.growth);
+        let mut tails = match em.query::<&mut Tail>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let mut eaten = vec![];
+        for event in self.apple_eaten.read(&apple_eaten) {
+            // The apple is only despawned once commands are applied, so it can be eaten twice
+            if eaten.contains(&event.apple) || em.has_component::<Apple>(event.apple) != Ok(true) {
+                continue;
+            }
+            eaten.push(event.apple);
+            commands.despawn(event.apple);
+            if let Some(mut tail) = tails.get_mut(event.snek) {
+                tail.pending += growth;
+            }
+            if let Ok(score) = score.as_mut() {
+                score.apples += 1;
+            }
+            if let Ok(time) = time.as_mut() {
+                let tick_rate = (time.tick_rate() + 1).min(MAX_TICK_RATE);
+                time.set_tick_rate(tick_rate);
             }
         }
     }
+
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .read::<Apple>()
+            .read::<Dead>()
+            .write::<Tail>()
+            .read_event::<AppleEaten>()
+            .read_event::<SnakeDied>()
+            .write_resource::<GameState>()
+            .write_resource::<Score>()
+            .write_resource::<Time>()
+            .read_resource::<GameConfig>()
+    }
 }
 
 impl System for AppleSpawningSystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, _action: &Action) {
-        let apple_id = eia.borrow_ids::<Apple>(em).unwrap()[0];
-        let arena_id = eia.borrow_ids::<Arena>(em).unwrap()[0];
-        let arena = em.borrow_component::<Arena>(arena_id).unwrap();
-        let (apple, position) = em
-            .borrow_component_pair_mut::<Apple, Position>(apple_id)
-            .unwrap();
-
-        if !apple.is_alive {
-            apple.is_alive = true;
-            let mut rng = rand::thread_rng();
-            position.x = rng.gen_range(1..arena.width - 1);
-            position.y = rng.gen_range(1..arena.height - 1);
+    fn update(&mut self, em: &EntityManager, eia: &mut EntityIdAccessor, commands: &mut Commands) {
+        match eia.borrow_ids::<Apple>(em) {
+            Ok(ids) if ids.is_empty() => (),
+            _ => return,
         }
+        let arena = match em.resource::<Arena>() {
+            Ok(arena) => arena,
+            Err(_) => return,
+        };
+        let mut rng = match em.resource_mut::<GameRng>() {
+            Ok(rng) => rng,
+            Err(_) => return,
+        };
+
+        commands.spawn().insert_bundle(AppleBundle {
+            position: Position {
+                x: rng.gen_range(1..arena.width - 1),
+                y: rng.gen_range(1..arena.height - 1),
+            },
+        });
     }
-}
 
-impl DebugSystem {
-    fn extract_snek_info(&self, id: usize, debug: &mut Debugging, em: &EntityManager) {
-        let (position, snek, collidable) = em
-            .borrow_component_triple_mut::<Position, Snek, Collidable>(id)
-            .unwrap();
-        {
-            debug.x = Some(position.x);
-            debug.y = Some(position.y);
-            debug.name = Some("Snek".to_string());
-            debug.is_alive = Some(snek.is_alive);
-            debug.collided = Some(collidable.collided);
-        }
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .read::<Apple>()
+            .read_resource::<Arena>()
+            .write_resource::<GameRng>()
     }
+}
 
-    fn extract_apple_info(&self, id: usize, debug: &mut Debugging, em: &EntityManager) {
-        let (position, apple, collidable) = em
-            .borrow_component_triple_mut::<Position, Apple, Collidable>(id)
-            .unwrap();
-        {
+impl DebugSystem {
+    fn extract_info(
+        &self,
+        ids: &[Entity],
+        name: &str,
+        query: &mut Query<(&Position, &mut Debugging)>,
+    ) {
+        for id in ids {
+            let (position, mut debug) = query.get_mut(*id).unwrap();
             debug.x = Some(position.x);
             debug.y = Some(position.y);
-            debug.name = Some("Apple".to_string());
-            debug.is_alive = Some(apple.is_alive);
-            debug.collided = Some(collidable.collided);
+            debug.name = Some(name.to_string());
         }
     }
 }
 
 impl System for DebugSystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, _action: &Action) {
-        let ids = eia.borrow_ids::<Type>(em).unwrap();
+    fn update(&mut self, em: &EntityManager, eia: &mut EntityIdAccessor, _commands: &mut Commands) {
+        // The id lists are taken before the query since they need to read `Debugging` too
+        let sneks = match eia.borrow_ids_filtered::<Debugging, (Changed<Position>, With<Snek>)>(em)
+        {
+            Ok(ids) => ids.clone(),
+            Err(_) => return,
+        };
+        let apples =
+            match eia.borrow_ids_filtered::<Debugging, (Changed<Position>, With<Apple>)>(em) {
+                Ok(ids) => ids.clone(),
+                Err(_) => return,
+            };
+        let mut query = match em.query::<(&Position, &mut Debugging)>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        self.extract_info(&sneks, "Snek", &mut query);
+        self.extract_info(&apples, "Apple", &mut query);
+    }
 
-        for id in ids {
-            let (typ, debugging) = em
-                .borrow_component_pair_mut::<Type, Debugging>(*id)
-                .unwrap();
-            match typ.typ {
-                Types::Snek => self.extract_snek_info(*id, debugging, em),
-                Types::Apple => self.extract_apple_info(*id, debugging, em),
-            }
-        }
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .read::<Position>()
+            .read::<Snek>()
+            .read::<Apple>()
+            .write::<Debugging>()
     }
 }
 
@@ -202,12 +413,10 @@ impl RenderSystem {
         self.window.print(
             &mut self.screen,
             format!(
-                "name: {}, position.x: {}, position.y: {}, is_alive: {}, collided: {}",
+                "name: {}, position.x: {}, position.y: {}",
                 debug.name.to_owned().unwrap(),
                 debug.x.unwrap(),
-                debug.y.unwrap(),
-                debug.is_alive.unwrap(),
-                debug.collided.unwrap()
+                debug.y.unwrap()
             ),
             &mut Pos::new(0, 23),
             Style::white(),
@@ -218,28 +427,54 @@ impl RenderSystem {
         self.window.print(
             &mut self.screen,
             format!(
-                "name: {}, position.x: {}, position.y: {}, is_alive: {}, collided: {}",
+                "name: {}, position.x: {}, position.y: {}",
                 debug.name.to_owned().unwrap(),
                 debug.x.unwrap(),
-                debug.y.unwrap(),
-                debug.is_alive.unwrap(),
-                debug.collided.unwrap()
+                debug.y.unwrap()
             ),
             &mut Pos::new(0, 24),
             Style::white(),
         );
     }
+
+    fn game_over_status(&mut self) {
+        self.window.print(
+            &mut self.screen,
+            "game over, press q to quit",
+            &mut Pos::new(0, 26),
+            Style::white(),
+        );
+    }
+
+    fn score_status(&mut self, score: &Score) {
+        self.window.print(
+            &mut self.screen,
+            format!("score: {}, seed: {}", score.apples, score.seed),
+            &mut Pos::new(0, 25),
+            Style::white(),
+        );
+    }
 }
 
 impl System for RenderSystem {
-    fn update(&mut self, em: &mut EntityManager, eia: &mut EntityIdAccessor, action: &Action) {
-        let entity_ids = eia.borrow_ids_for_pair::<Render, Position>(em).unwrap();
+    fn update(
+        &mut self,
+        em: &EntityManager,
+        _eia: &mut EntityIdAccessor,
+        _commands: &mut Commands,
+    ) {
+        let query = match em.query::<(&Render, &Position)>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let debugs = match em.query::<&Debugging>() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
         self.screen
             .erase_region(Pos::new(10, 1), Size::new(140, 40));
-        for id in entity_ids.iter() {
-            let render = em.borrow_component::<Render>(*id).unwrap();
-            let position = em.borrow_component::<Position>(*id).unwrap();
-            let debug = em.borrow_component::<Debugging>(*id);
+        for (id, (render, position)) in query.iter() {
+            let debug = debugs.get(id);
 
             self.window.put_sprite(
                 &mut self.screen,
@@ -248,22 +483,40 @@ impl System for RenderSystem {
                 Style::white(),
             );
 
+            // Debug info without a name is not shown
             if let Some(debug) = debug {
-                match &debug.name {
-                    Some(name) => {
-                        if name == "Snek" {
-                            self.snake_stats(debug);
-                        } else if name == "Apple" {
-                            self.apple_status(debug);
-                        }
-                    }
-                    None => todo!(),
+                match debug.name.as_deref() {
+                    Some("Snek") => self.snake_stats(debug),
+                    Some("Apple") => self.apple_status(debug),
+                    _ => (),
                 }
             }
         }
+        if let Ok(score) = em.resource::<Score>() {
+            self.score_status(&score);
+        }
+        if em
+            .resource::<GameState>()
+            .is_ok_and(|state| *state == GameState::GameOver)
+        {
+            self.game_over_status();
+        }
         self.screen.render().unwrap();
-        if action == &Action::Exit {
+        let exit = em
+            .resource::<Input>()
+            .is_ok_and(|input| input.pressed(&Action::Exit));
+        if exit {
             self.screen.disable_raw_mode().unwrap();
         }
     }
+
+    fn access(&self) -> SystemAccess {
+        SystemAccess::new()
+            .read::<Render>()
+            .read::<Position>()
+            .read::<Debugging>()
+            .read_resource::<Score>()
+            .read_resource::<GameState>()
+            .read_resource::<Input>()
+    }
 }
@@ -1,17 +1,65 @@
-pub mod core;
-
 use std::env::args;
 
-use crate::core::game::Game;
+use snake::core::{game::Game, gui::screen::Screen};
 
 fn main() {
-    let args: Vec<String> = args().collect();
-    let height = &args[1];
-    let width = &args[2];
+    let args: Vec<String> = args().skip(1).collect();
+    let (positional, seed) = parse_args(&args);
+    let height = positional[0];
+    let width = positional[1];
 
-    let screen = core::gui::screen::Screen::stdout()
-        .unwrap()
-        .alternate_screen(false);
+    let screen = Screen::stdout().unwrap().alternate_screen(false);
     screen.enable_raw_mode().unwrap();
-    Game::new(screen, height.parse().unwrap(), width.parse().unwrap()).run();
+    match Game::new(
+        screen,
+        height.parse().unwrap(),
+        width.parse().unwrap(),
+        seed,
+    ) {
+        Ok(mut game) => game.run(),
+        Err(error) => {
+            crossterm::terminal::disable_raw_mode().unwrap();
+            eprintln!("could not start the game: {}", error);
+        }
+    }
+}
+
+/// Splits the arguments into the positional ones and the value of `--seed`, wherever it is
+fn parse_args(args: &[String]) -> (Vec<&str>, Option<u64>) {
+    let mut positional = vec![];
+    let mut seed = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--seed" {
+            let value = args.next().expect("--seed takes a number");
+            seed = Some(value.parse().expect("--seed takes a number"));
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+    (positional, seed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    pub fn should_parse_seed_before_and_after_size() {
+        let after = strings(&["20", "30", "--seed", "7"]);
+        let before = strings(&["--seed", "7", "20", "30"]);
+        let between = strings(&["20", "--seed", "7", "30"]);
+
+        assert_eq!(parse_args(&after), (vec!["20", "30"], Some(7)));
+        assert_eq!(parse_args(&before), (vec!["20", "30"], Some(7)));
+        assert_eq!(parse_args(&between), (vec!["20", "30"], Some(7)));
+        assert_eq!(
+            parse_args(&strings(&["20", "30"])),
+            (vec!["20", "30"], None)
+        );
+    }
 }